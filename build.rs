@@ -0,0 +1,17 @@
+use std::process::Command;
+
+fn main() {
+    // Use the commit given by the build environment, or ask git for it.
+    let commit = std::env::var("GIT_HASH").ok()
+        .or_else(|| {
+            let output = Command::new("git").args(["rev-parse", "--short", "HEAD"]).output().ok()?;
+            output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        })
+        .filter(|commit| !commit.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned());
+
+    println!("cargo:rustc-env=GIT_HASH={commit}");
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -1,7 +1,9 @@
-use std::{io, path::PathBuf};
+use std::{io, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
 
-use clap::{arg, Command, ArgMatches};
-use rcgen::generate_simple_self_signed;
+use clap::{arg, Arg, ArgAction, Command, ArgMatches};
+use rand::distributions::{Alphanumeric, DistString};
+use rcgen::{Certificate, CertificateParams, KeyPair, SanType};
+use rsa::{RsaPrivateKey, pkcs8::EncodePrivateKey};
 
 mod run;
 
@@ -15,24 +17,71 @@ fn cli() -> Command {
             Command::new("new")
                 .about("Create a new pyne server")
                 .arg(arg!(name: <NAME> "Name of the new server"))
+                .args(cert_args())
                 .arg_required_else_help(true),
         )
+        .subcommand(
+            Command::new("rotate-token")
+                .about("Replace the auth token of a pyne server")
+                .arg(arg!(path: [PATH] "Server instance directory").value_parser(clap::value_parser!(PathBuf)).default_value("."))
+                .arg(arg!(--grace <SECONDS> "Keep accepting the old token for this many seconds").value_parser(clap::value_parser!(u64))),
+        )
+        .subcommand(
+            Command::new("regen-cert")
+                .about("Replace the TLS certificate of a pyne server, backing up the old one")
+                .arg(arg!(path: [PATH] "Server instance directory").value_parser(clap::value_parser!(PathBuf)).default_value("."))
+                .args(cert_args()),
+        )
         .subcommand(
             Command::new("run")
                 .about("Run a pyne server")
                 // .arg(arg!(path: <PATH> "Path to the server directory").default_value(".").value_parser(clap::value_parser!(PathBuf)))
-                .arg(arg!(port: <PORT> "Server listening port"))
+                .arg(arg!(port: [PORT] "Server listening port, defaults to the `port` in the config (8443)"))
+                .arg(arg!(host: -H --host <HOST> "Address or hostname to listen on").default_value("127.0.0.1"))
                 .arg(arg!(path: <PATH> "Server instance directory").required(false).value_parser(clap::value_parser!(PathBuf)).default_value("."))
+                .arg(arg!(--"max-body-size" <BYTES> "Maximum size of request bodies, overriding the config").value_parser(clap::value_parser!(u64)))
+                .arg(arg!(--timeout <SECONDS> "Seconds clients get to send their request, overriding the config").value_parser(clap::value_parser!(u64)))
+                .arg(arg!(--"cors-origin" <ORIGIN> "Origin browsers may read responses from, can be repeated, overriding the config").action(ArgAction::Append))
+                .arg(arg!(--unix <SOCKET> "Listen on a Unix domain socket (without TLS) instead of the TCP port").value_parser(clap::value_parser!(PathBuf)))
         )
 }
 
+/// Options for generating the TLS certificate of a server.
+fn cert_args() -> [Arg; 2] {
+    [
+        arg!(--san <NAME> "Hostname or IP address the certificate is valid for, can be repeated")
+            .action(ArgAction::Append)
+            .default_values(["localhost", "127.0.0.1"]),
+        arg!(--"key-type" <TYPE> "Type of the certificate key")
+            .value_parser(["ecdsa", "rsa"])
+            .default_value("ecdsa"),
+    ]
+}
+
 #[tokio::main]
 async fn main() {
     let matches = cli().get_matches();
 
     match matches.subcommand() {
         Some(("new", matches)) => cmd_new(matches),
-        Some(("run", matches)) => run::start(matches).await.unwrap(),
+        Some(("rotate-token", matches)) => {
+            if let Err(err) = cmd_rotate_token(matches) {
+                eprintln!("Failed to rotate token: {err}");
+                std::process::exit(1);
+            }
+        }
+        Some(("regen-cert", matches)) => {
+            if let Err(err) = cmd_regen_cert(matches) {
+                eprintln!("Failed to regenerate certificate: {err}");
+                std::process::exit(1);
+            }
+        }
+        Some(("run", matches)) => {
+            if let Err(err) = run::start(matches).await {
+                eprintln!("Failed to run server: {err}");
+                std::process::exit(1);
+            }
+        }
         _ => unreachable!(),
     };
 }
@@ -47,19 +96,111 @@ fn cmd_new(args: &ArgMatches) {
     std::fs::create_dir(format!("{name}/notes")).unwrap();
 
     // Generate the TLS files.
-    gen_cert(format!("{name}/server.crt").into(), format!("{name}/server.key").into()).unwrap();
-    
+    gen_cert(format!("{name}/server.crt").into(), format!("{name}/server.key").into(), args).unwrap();
+
+    // Generate the auth token.
+    let token = gen_token();
+    std::fs::write(format!("{name}/config.toml"), format!("token = \"{token}\"\n")).unwrap();
+
     println!("Created server (instance) `{name}` at `./{name}/*`");
+    println!("Auth token: {token}");
+}
+
+fn cmd_rotate_token(args: &ArgMatches) -> io::Result<()> {
+    let path = args.get_one::<PathBuf>("path").expect("Missing path.").join("config.toml");
+    let content = std::fs::read_to_string(&path)?;
+    let config: toml::Table = content.parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    let token = gen_token();
+    let mut lines = vec![format!("token = {}", toml::Value::from(token.as_str()))];
+
+    // Keep the old token around for clients that haven't been updated yet.
+    if let (Some(grace), Some(old)) = (args.get_one::<u64>("grace"), config.get("token")) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        lines.push(format!("previous_token = {old}"));
+        lines.push(format!("previous_token_expires = {}", now + grace));
+    }
+
+    // Leave the rest of the config (and its comments) as it was.
+    lines.extend(content.lines().filter(|line| !is_token_line(line)).map(str::to_owned));
+    std::fs::write(&path, lines.join("\n") + "\n")?;
+
+    println!("New auth token: {token}");
+    println!("Restart the server to start using it.");
+    Ok(())
 }
 
-fn gen_cert(certfile: PathBuf, keyfile: PathBuf) -> io::Result<()> {
-    let subject_alt_names: &[_] = &["tls-server".to_string()];
-    let cert = generate_simple_self_signed(subject_alt_names).unwrap();
+fn cmd_regen_cert(args: &ArgMatches) -> io::Result<()> {
+    let path = args.get_one::<PathBuf>("path").expect("Missing path.");
+
+    if !path.join("server.crt").is_file() || !path.join("notes").is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("`{}` is not a pyne server directory.", path.display())));
+    }
+
+    // Generate next to the old files first, so a failure leaves them in place.
+    let names = ["server.crt", "server.key"];
+    let [new_cert, new_key] = names.map(|name| path.join(format!("{name}.new")));
+    if let Err(err) = gen_cert(new_cert.clone(), new_key.clone(), args) {
+        let _ = std::fs::remove_file(&new_cert);
+        let _ = std::fs::remove_file(&new_key);
+        return Err(err);
+    }
+
+    // Back up the old files, named after when they were replaced, and swap in the new ones.
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    for (name, new) in names.into_iter().zip([new_cert, new_key]) {
+        let file = path.join(name);
+        if file.exists() {
+            let backup = path.join(format!("{name}.{now}.bak"));
+            std::fs::rename(&file, &backup)?;
+            println!("Backed up `{}` to `{}`", file.display(), backup.display());
+        }
+        std::fs::rename(&new, &file)?;
+    }
+
+    println!("Generated a new certificate at `{}`", path.join("server.crt").display());
+    println!("Restart the server to start using it.");
+    Ok(())
+}
+
+/// Check if a config line sets one of the auth tokens.
+fn is_token_line(line: &str) -> bool {
+    line.split_once('=')
+        .is_some_and(|(key, _)| matches!(key.trim(), "token" | "previous_token" | "previous_token_expires"))
+}
+
+fn gen_token() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 32)
+}
+
+fn gen_cert(certfile: PathBuf, keyfile: PathBuf, args: &ArgMatches) -> io::Result<()> {
+    let cert_err = |err: rcgen::RcgenError| io::Error::other(err.to_string());
+
+    // Addresses need their own kind of SAN entry, anything else is a DNS name.
+    let mut params = CertificateParams::default();
+    params.subject_alt_names = args.get_many::<String>("san").unwrap_or_default()
+        .map(|san| match san.parse() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(san.to_owned()),
+        })
+        .collect();
+
+    // rcgen only generates EC keys by itself.
+    if args.get_one::<String>("key-type").map(String::as_str) == Some("rsa") {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048)
+            .and_then(|key| key.to_pkcs8_der().map_err(rsa::Error::from))
+            .map_err(|err| io::Error::other(err.to_string()))?;
+
+        params.alg = &rcgen::PKCS_RSA_SHA256;
+        params.key_pair = Some(KeyPair::from_der(key.as_bytes()).map_err(cert_err)?);
+    }
+    let cert = Certificate::from_params(params).map_err(cert_err)?;
 
     if let Some(dir) = certfile.parent() {
         std::fs::create_dir_all(dir)?;
     }
-    std::fs::write(certfile, cert.serialize_pem().unwrap())?;
+    std::fs::write(certfile, cert.serialize_pem().map_err(cert_err)?)?;
 
     if let Some(dir) = keyfile.parent() {
         std::fs::create_dir_all(dir)?;
@@ -68,3 +209,119 @@ fn gen_cert(certfile: PathBuf, keyfile: PathBuf) -> io::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::testing::TempDir;
+
+    fn subcommand(args: &[&str]) -> ArgMatches {
+        let matches = cli().try_get_matches_from([&["pyne"], args].concat()).unwrap();
+        matches.subcommand().unwrap().1.clone()
+    }
+
+    #[test]
+    fn new_generates_a_random_token() {
+        let parent = TempDir::new("new");
+        let mut tokens = Vec::new();
+
+        for name in ["a", "b"] {
+            let dir = parent.join(name);
+            cmd_new(&subcommand(&["new", dir.to_str().unwrap()]));
+
+            let config: toml::Table = std::fs::read_to_string(dir.join("config.toml")).unwrap().parse().unwrap();
+            let token = config["token"].as_str().unwrap().to_owned();
+            assert_eq!(token.len(), 32);
+            assert!(token.chars().all(|c| c.is_ascii_alphanumeric()));
+            tokens.push(token);
+        }
+        assert_ne!(tokens[0], tokens[1]);
+    }
+
+    #[test]
+    fn rotate_token_replaces_the_token_and_keeps_the_rest() {
+        let dir = TempDir::new("rotate-token");
+        let path = dir.path().to_str().unwrap();
+        std::fs::write(dir.join("config.toml"), "# My server\ntoken = \"old\"\nport = 9000\n").unwrap();
+        let config = || -> toml::Table { std::fs::read_to_string(dir.join("config.toml")).unwrap().parse().unwrap() };
+
+        cmd_rotate_token(&subcommand(&["rotate-token", path])).unwrap();
+        let rotated = config();
+        let token = rotated["token"].as_str().unwrap().to_owned();
+        assert_ne!(token, "old");
+        assert_eq!(rotated["port"].as_integer(), Some(9000));
+        assert!(!rotated.contains_key("previous_token"));
+        assert!(std::fs::read_to_string(dir.join("config.toml")).unwrap().contains("# My server\n"));
+
+        // With a grace period, the old token stays valid for a while.
+        cmd_rotate_token(&subcommand(&["rotate-token", path, "--grace", "60"])).unwrap();
+        let graced = config();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        assert_ne!(graced["token"].as_str(), Some(token.as_str()));
+        assert_eq!(graced["previous_token"].as_str(), Some(token.as_str()));
+        assert!((now + 50..=now + 60).contains(&graced["previous_token_expires"].as_integer().unwrap()));
+    }
+
+    /// Generate a certificate with `new`'s certificate options, returning its DER encoding.
+    fn generated_cert(dir: &TempDir, args: &[&str]) -> Vec<u8> {
+        let name = dir.join("name");
+        let matches = subcommand(&[&["new", name.to_str().unwrap()], args].concat());
+        gen_cert(dir.join("server.crt"), dir.join("server.key"), &matches).unwrap();
+
+        let pem = std::fs::read(dir.join("server.crt")).unwrap();
+        rustls_pemfile::certs(&mut pem.as_slice()).unwrap().remove(0)
+    }
+
+    fn contains(der: &[u8], bytes: &[u8]) -> bool {
+        der.windows(bytes.len()).any(|window| window == bytes)
+    }
+
+    #[test]
+    fn certificates_contain_the_requested_sans() {
+        // SAN entries are tagged DNS names (0x82) and IP addresses (0x87).
+        let dns = |name: &str| [&[0x82, name.len() as u8], name.as_bytes()].concat();
+        const ECDSA_SHA256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+        const RSA_SHA256: &[u8] = &[0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+
+        let dir = TempDir::new("cert-defaults");
+        let default = generated_cert(&dir, &[]);
+        assert!(contains(&default, &dns("localhost")));
+        assert!(contains(&default, &[0x87, 4, 127, 0, 0, 1]));
+        assert!(contains(&default, ECDSA_SHA256));
+
+        let dir = TempDir::new("cert-sans");
+        let custom = generated_cert(&dir, &["--san", "notes.example", "--san", "192.168.1.20", "--key-type", "rsa"]);
+        assert!(contains(&custom, &dns("notes.example")));
+        assert!(contains(&custom, &[0x87, 4, 192, 168, 1, 20]));
+        assert!(!contains(&custom, &dns("localhost")));
+        assert!(contains(&custom, RSA_SHA256));
+    }
+
+    #[test]
+    fn regen_cert_backs_up_and_replaces_the_certificate() {
+        let parent = TempDir::new("regen-cert");
+        let instance = parent.join("server");
+        let path = instance.to_str().unwrap();
+
+        // Directories that aren't instances are left alone.
+        let err = cmd_regen_cert(&subcommand(&["regen-cert", parent.path().to_str().unwrap()])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!parent.join("server.crt").exists());
+
+        cmd_new(&subcommand(&["new", path]));
+        let old = std::fs::read(instance.join("server.crt")).unwrap();
+
+        cmd_regen_cert(&subcommand(&["regen-cert", path, "--san", "notes.example"])).unwrap();
+        let new = std::fs::read(instance.join("server.crt")).unwrap();
+        assert_ne!(new, old);
+        assert!(instance.join("server.key").is_file() && !instance.join("server.crt.new").exists());
+
+        let backups: Vec<String> = std::fs::read_dir(&instance).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".bak"))
+            .collect();
+        assert_eq!(backups.len(), 2);
+        let backup = backups.iter().find(|name| name.starts_with("server.crt.")).unwrap();
+        assert_eq!(std::fs::read(instance.join(backup)).unwrap(), old);
+    }
+}
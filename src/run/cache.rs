@@ -0,0 +1,112 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::SystemTime};
+
+/// Least recently used cache of note contents, bounded by their total size in bytes.
+#[derive(Debug, Default)]
+pub(crate) struct NoteCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Cached contents, with the modification time they were read at and the tick they were last used at.
+    entries: HashMap<PathBuf, (Arc<Vec<u8>>, SystemTime, u64)>,
+    size: usize,
+    tick: u64,
+}
+
+impl NoteCache {
+    /// Create a new cache holding at most `capacity` bytes, zero disables caching.
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, inner: Mutex::new(Inner::default()) }
+    }
+
+    /// Get the cached content of a note, unless the note has changed since, going by its modification time and length.
+    pub fn get(&self, path: &Path, modified: SystemTime, len: u64) -> Option<Arc<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+
+        let tick = inner.tick;
+        let (content, cached, used) = inner.entries.get_mut(path)?;
+        if *cached != modified || content.len() as u64 != len {
+            return None;
+        }
+        *used = tick;
+        Some(content.clone())
+    }
+
+    /// Cache the content of a note as of its modification time, evicting the least recently used notes to make room.
+    pub fn insert(&self, path: &Path, modified: SystemTime, content: &[u8]) {
+        if self.capacity == 0 || content.len() > self.capacity {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+
+        if let Some((old, ..)) = inner.entries.remove(path) {
+            inner.size -= old.len();
+        }
+
+        while inner.size + content.len() > self.capacity {
+            let Some(oldest) = inner.entries.iter().min_by_key(|(_, (.., used))| *used).map(|(path, _)| path.clone()) else {
+                break;
+            };
+            if let Some((old, ..)) = inner.entries.remove(&oldest) {
+                inner.size -= old.len();
+            }
+        }
+
+        let tick = inner.tick;
+        inner.size += content.len();
+        inner.entries.insert(path.to_owned(), (Arc::new(content.to_vec()), modified, tick));
+    }
+
+    /// Drop a note from the cache, e.g. because it was written or deleted.
+    pub fn invalidate(&self, path: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some((old, ..)) = inner.entries.remove(path) {
+            inner.size -= old.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn zero_capacity_caches_nothing() {
+        let cache = NoteCache::new(0);
+        cache.insert(Path::new("empty.md"), SystemTime::UNIX_EPOCH, b"");
+        assert!(cache.get(Path::new("empty.md"), SystemTime::UNIX_EPOCH, 0).is_none());
+    }
+
+    #[test]
+    fn changed_notes_miss() {
+        let cache = NoteCache::new(16);
+        let (path, modified) = (Path::new("a.md"), SystemTime::UNIX_EPOCH);
+        cache.insert(path, modified, b"old");
+
+        assert_eq!(cache.get(path, modified, 3).as_deref().map(Vec::as_slice), Some(&b"old"[..]));
+        assert!(cache.get(path, modified + Duration::from_secs(1), 3).is_none());
+        assert!(cache.get(path, modified, 4).is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = NoteCache::new(8);
+        let modified = SystemTime::UNIX_EPOCH;
+        cache.insert(Path::new("a"), modified, b"aaaa");
+        cache.insert(Path::new("b"), modified, b"bbbb");
+        cache.get(Path::new("a"), modified, 4);
+        cache.insert(Path::new("c"), modified, b"cccc");
+
+        assert!(cache.get(Path::new("a"), modified, 4).is_some());
+        assert!(cache.get(Path::new("b"), modified, 4).is_none());
+        assert!(cache.get(Path::new("c"), modified, 4).is_some());
+    }
+}
@@ -0,0 +1,227 @@
+use std::{collections::HashMap, fs, io, net::IpAddr, path::{Path, PathBuf}};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize, Serializer};
+
+/// Server configuration, read from `config.toml` in the instance directory.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub(crate) struct ServerConfig {
+    /// Trim trailing whitespace of each line and end with a single newline when writing notes.
+    pub normalize_whitespace: bool,
+    /// Maximum time in seconds a request may take to be handled.
+    pub request_deadline: u64,
+    /// Resolve note paths to existing notes ignoring case.
+    pub case_insensitive_paths: bool,
+    /// Keep the notes listing in memory, built at startup.
+    pub cache_listing: bool,
+    /// Build each response in memory and send it in one write, instead of streaming the body.
+    pub buffer_responses: bool,
+    /// Leave symlinks pointing at nothing out of the notes listing.
+    pub skip_broken_symlinks: bool,
+    /// Plaintext port answering every request with a redirect to HTTPS.
+    pub http_redirect_port: Option<u16>,
+    /// Send generic 500 bodies, and only log the error details server-side.
+    pub production: bool,
+    /// Slugify the names of notes when they're written (e.g. `My Note!.md` to `my-note.md`).
+    pub slugify_names: bool,
+    /// Number of TLS sessions kept around for resumption, zero disables resumption.
+    pub tls_session_cache: usize,
+    /// Directory with `404.html` and `500.html` pages served to HTML clients.
+    pub error_pages: Option<PathBuf>,
+    /// Seconds a deleted note is reported as 410 Gone instead of 404, zero disables this.
+    pub tombstone_window: u64,
+    /// Name of the request header carrying the auth token.
+    pub auth_header: String,
+    /// Minimum TLS version accepted, either "1.2" or "1.3".
+    pub tls_min_version: String,
+    /// Append `.md` to the names of written notes which have no extension.
+    pub append_md_extension: bool,
+    /// File the metrics are periodically saved to, and continued from at startup.
+    pub metrics_file: Option<PathBuf>,
+    /// Seconds between saving the metrics to the metrics file.
+    pub metrics_flush_interval: u64,
+    /// Refuse note writes with an empty body, unless `?empty=true` is used.
+    pub require_body: bool,
+    /// Add `; charset=utf-8` to the content type of text notes.
+    pub utf8_charset: bool,
+    /// Total size in bytes of the note contents kept in memory, zero disables the cache.
+    pub note_cache_bytes: usize,
+    /// Number of notes statted in parallel when listing the notes directory.
+    pub listing_concurrency: usize,
+    /// Answer TRACE requests by echoing the request back, for debugging proxies.
+    pub debug_trace: bool,
+    /// Log the request line and headers of each request at debug level, with credentials redacted.
+    pub log_headers: bool,
+    /// Serve and list notes whose names begin with a dot.
+    pub serve_hidden: bool,
+    /// Auth token clients have to send, unless it's set with the `PYNE_TOKEN` environment variable.
+    #[serde(serialize_with = "redact")]
+    pub token: Option<String>,
+    /// Token replaced by `pyne rotate-token`, which stays valid until `previous_token_expires`.
+    #[serde(serialize_with = "redact")]
+    pub previous_token: Option<String>,
+    /// Unix time in seconds at which the previous token stops being accepted.
+    pub previous_token_expires: Option<u64>,
+    /// Maximum length in bytes of a request target, longer ones are answered with 414.
+    pub max_path_length: usize,
+    /// Maximum size in bytes of a request body, larger ones are answered with 413 (see `--max-body-size`).
+    pub max_body_size: u64,
+    /// Addresses or CIDR ranges clients may connect from, any client may connect when empty.
+    pub ip_allowlist: Vec<IpNet>,
+    /// Seconds a client gets for the TLS handshake and for sending its request (see `--timeout`).
+    pub read_timeout: u64,
+    /// Port to listen on when `run` isn't given one.
+    pub port: u16,
+    /// Seconds a connection may go without sending or receiving data before it's closed, 0 keeps them open.
+    pub idle_timeout: u64,
+    /// Origins browsers may read responses from, `*` allows any (see `--cors-origin`).
+    pub cors_origins: Vec<String>,
+    /// Send a `Digest` header with the SHA-256 of notes that are read.
+    pub note_digests: bool,
+    /// Answer writes to a note that is already being written with 409, instead of waiting for their turn.
+    pub reject_concurrent_writes: bool,
+    /// File log records are appended to besides stderr, which `/logs` reads from.
+    pub log_file: Option<PathBuf>,
+    /// Named notes directories besides `notes`, served under `/c/<name>/notes`.
+    pub collections: HashMap<String, PathBuf>,
+    /// Warn about responses taking longer than this many milliseconds to handle and send, 0 disables the warning.
+    pub slow_response_ms: u64,
+    /// Warn about response bodies bigger than this many bytes, 0 disables the warning.
+    pub large_response_bytes: u64,
+    /// Number of matches `/search` returns when it isn't given a `limit`.
+    pub search_limit: usize,
+    /// Longest snippet of a matching line `/search` returns in characters, unless it's given a `snippet_length`.
+    pub search_snippet_length: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            normalize_whitespace: false,
+            request_deadline: 30,
+            case_insensitive_paths: false,
+            cache_listing: false,
+            buffer_responses: true,
+            skip_broken_symlinks: false,
+            http_redirect_port: None,
+            production: false,
+            slugify_names: false,
+            tls_session_cache: 256,
+            error_pages: None,
+            tombstone_window: 3600,
+            auth_header: "Authorization".to_owned(),
+            tls_min_version: "1.2".to_owned(),
+            append_md_extension: false,
+            metrics_file: None,
+            metrics_flush_interval: 60,
+            require_body: false,
+            utf8_charset: true,
+            note_cache_bytes: 0,
+            listing_concurrency: 8,
+            debug_trace: false,
+            log_headers: false,
+            serve_hidden: false,
+            token: None,
+            previous_token: None,
+            previous_token_expires: None,
+            max_path_length: 8192,
+            max_body_size: 8 * 1024 * 1024,
+            ip_allowlist: Vec::new(),
+            read_timeout: 10,
+            port: 8443,
+            idle_timeout: 60,
+            cors_origins: vec!["*".to_owned()],
+            note_digests: false,
+            reject_concurrent_writes: false,
+            log_file: None,
+            collections: HashMap::new(),
+            slow_response_ms: 1000,
+            large_response_bytes: 0,
+            search_limit: 50,
+            search_snippet_length: 200,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Check if a client address may connect.
+    pub fn allows_ip(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.ip_allowlist.is_empty() || self.ip_allowlist.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Make the paths in the config relative to the instance directory.
+    pub fn resolve_paths(&mut self, instance: &Path) {
+        self.error_pages = self.error_pages.take().map(|dir| instance.join(dir));
+        self.metrics_file = self.metrics_file.take().map(|file| instance.join(file));
+        self.log_file = self.log_file.take().map(|file| instance.join(file));
+        for dir in self.collections.values_mut() {
+            *dir = instance.join(&dir);
+        }
+    }
+
+    /// Load the config file, falling back to the defaults if it doesn't exist.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            // The error points at the line and column that are wrong.
+            Ok(content) => toml::from_str(&content).map_err(|err| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Invalid config `{}`: {err}", path.display()))
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Serialize a secret without revealing it.
+fn redact<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_str("***"),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::testing::TempDir;
+
+    #[test]
+    fn allowlist_takes_addresses_and_ranges() {
+        let config = ServerConfig {
+            ip_allowlist: vec!["192.168.1.0/24".parse().unwrap(), "::1/128".parse().unwrap()],
+            ..ServerConfig::default()
+        };
+
+        assert!(config.allows_ip("192.168.1.42".parse().unwrap()));
+        assert!(config.allows_ip("::1".parse().unwrap()));
+        // IPv4 clients of a dual-stack listener show up as mapped IPv6 addresses.
+        assert!(config.allows_ip("::ffff:192.168.1.42".parse().unwrap()));
+
+        assert!(!config.allows_ip("192.168.2.1".parse().unwrap()));
+        assert!(!config.allows_ip("127.0.0.1".parse().unwrap()));
+
+        // Without an allowlist, everyone may connect.
+        assert!(ServerConfig::default().allows_ip("203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn malformed_config_reports_the_file_and_line() {
+        let dir = TempDir::new("malformed-config");
+        let path = dir.join("config.toml");
+
+        fs::write(&path, "port = 8443\nread_timeout = \"soon\"\n").unwrap();
+        let err = ServerConfig::load(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let message = err.to_string();
+        assert!(message.starts_with(&format!("Invalid config `{}`", path.display())), "{message}");
+        assert!(message.contains("line 2") && message.contains("read_timeout"), "{message}");
+
+        fs::write(&path, "port = \n").unwrap();
+        assert!(ServerConfig::load(&path).unwrap_err().to_string().contains("line 1"));
+
+        // A missing config isn't an error.
+        assert_eq!(ServerConfig::load(&dir.join("missing.toml")).unwrap().port, ServerConfig::default().port);
+    }
+}
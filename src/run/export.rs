@@ -0,0 +1,68 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{frontmatter, http::civil_date};
+
+/// Format a note can be exported in, for static site generators.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ExportFormat {
+    /// Markdown with YAML front-matter, as read by Hugo.
+    Hugo,
+}
+
+impl ExportFormat {
+    /// Get the export format from its name in a query string.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "hugo" => Some(Self::Hugo),
+            _ => None,
+        }
+    }
+
+    /// Add the front-matter of this format to a note, keeping the fields its own front-matter already sets.
+    pub fn render(self, title: &str, modified: SystemTime, content: &str) -> String {
+        match self {
+            Self::Hugo => {
+                // Tags come from the note's own front-matter, JSON strings are valid YAML and take care of escaping.
+                let (note, _) = frontmatter::retag(content, &[], &[]);
+                let title = serde_json::Value::from(title).to_string();
+                frontmatter::with_defaults(&note, &[("title", title), ("date", rfc3339(modified))])
+            }
+        }
+    }
+}
+
+/// Format a time as an RFC 3339 date, e.g. `1994-11-06T08:49:37Z`.
+fn rfc3339(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_date(days);
+
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z", rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn hugo_export_prepends_front_matter() {
+        let modified = UNIX_EPOCH + Duration::from_secs(784_111_777);
+
+        let note = ExportFormat::Hugo.render("my \"idea\"", modified, "Hello\n");
+        assert_eq!(note, "---\ntitle: \"my \\\"idea\\\"\"\ndate: 1994-11-06T08:49:37Z\ntags: []\n---\n\nHello\n");
+    }
+
+    #[test]
+    fn hugo_export_keeps_the_notes_own_fields_and_tags() {
+        let modified = UNIX_EPOCH + Duration::from_secs(784_111_777);
+
+        let note = ExportFormat::Hugo.render("idea", modified, "---\ntitle: Mine\ntags: [work, home]\n---\nHello\n");
+        assert!(note.starts_with("---\ndate: 1994-11-06T08:49:37Z\ntitle: Mine\n"), "{note}");
+        assert!(note.contains("work") && note.contains("home"), "{note}");
+        assert!(note.ends_with("---\nHello\n"), "{note}");
+
+        assert!(ExportFormat::parse("jekyll").is_none());
+    }
+}
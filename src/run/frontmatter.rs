@@ -0,0 +1,152 @@
+/// Split a note into its YAML front-matter and the content after it, if it has front-matter.
+fn split(content: &str) -> Option<(&str, &str)> {
+    let rest = content.strip_prefix("---\n").or_else(|| content.strip_prefix("---\r\n"))?;
+    let mut offset = 0;
+
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == "---" {
+            return Some((&rest[..offset], &rest[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+/// Remove the quotes around a YAML scalar.
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    serde_json::from_str::<String>(value).unwrap_or_else(|_| value.trim_matches('\'').to_owned())
+}
+
+/// Parse an inline YAML list, e.g. `[work, "to do"]`.
+fn parse_list(value: &str) -> Vec<String> {
+    if let Ok(tags) = serde_json::from_str::<Vec<String>>(value) {
+        return tags;
+    }
+    value.trim_start_matches('[').trim_end_matches(']')
+        .split(',')
+        .map(unquote)
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+/// Add and remove tags in the front-matter of a note, creating the front-matter if it has none.
+pub fn retag(content: &str, add: &[String], remove: &[String]) -> (String, Vec<String>) {
+    let existing = split(content);
+    let (yaml, body) = existing.unwrap_or(("", content));
+    let mut lines = Vec::new();
+    let mut tags = Vec::new();
+    let mut in_tags = false;
+
+    for line in yaml.lines() {
+        // Tags listed on their own lines below `tags:`.
+        if in_tags {
+            if let Some(tag) = line.trim_start().strip_prefix('-') {
+                tags.push(unquote(tag));
+                continue;
+            }
+            in_tags = false;
+        }
+
+        if let Some(value) = line.strip_prefix("tags:") {
+            if value.trim().is_empty() {
+                in_tags = true;
+            } else {
+                tags.extend(parse_list(value.trim()));
+            }
+            continue;
+        }
+        lines.push(line);
+    }
+
+    tags.retain(|tag| !remove.contains(tag));
+    for tag in add {
+        if !tags.contains(tag) {
+            tags.push(tag.clone());
+        }
+    }
+
+    // JSON arrays are valid YAML, and take care of escaping.
+    let mut note = String::from("---\n");
+    for line in lines {
+        note.push_str(line);
+        note.push('\n');
+    }
+    note.push_str(&format!("tags: {}\n---\n", serde_json::Value::from(tags.as_slice())));
+    if existing.is_none() {
+        note.push('\n');
+    }
+    note.push_str(body);
+
+    (note, tags)
+}
+
+/// Add fields to the front-matter of a note that it doesn't set yet, creating the front-matter if it has none.
+pub fn with_defaults(content: &str, fields: &[(&str, String)]) -> String {
+    let existing = split(content);
+    let (yaml, body) = existing.unwrap_or(("", content));
+
+    let mut note = String::from("---\n");
+    for (key, value) in fields {
+        if !yaml.lines().any(|line| line.strip_prefix(key).is_some_and(|rest| rest.starts_with(':'))) {
+            note.push_str(&format!("{key}: {value}\n"));
+        }
+    }
+    note.push_str(yaml);
+    note.push_str("---\n");
+    if existing.is_none() {
+        note.push('\n');
+    }
+    note.push_str(body);
+
+    note
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|&value| value.to_owned()).collect()
+    }
+
+    #[test]
+    fn retag_creates_front_matter() {
+        let (note, tags) = retag("Hello\n", &strings(&["work"]), &[]);
+        assert_eq!(note, "---\ntags: [\"work\"]\n---\n\nHello\n");
+        assert_eq!(tags, ["work"]);
+    }
+
+    #[test]
+    fn retag_edits_inline_lists() {
+        let note = "---\ntitle: Plan\ntags: [work, \"to do\"]\n---\nBody\n";
+        let (note, tags) = retag(note, &strings(&["home", "work"]), &strings(&["to do"]));
+        assert_eq!(note, "---\ntitle: Plan\ntags: [\"work\",\"home\"]\n---\nBody\n");
+        assert_eq!(tags, ["work", "home"]);
+    }
+
+    #[test]
+    fn retag_edits_block_lists() {
+        let note = "---\ntags:\n  - work\n  - 'to do'\ndraft: true\n---\nBody\n";
+        let (note, tags) = retag(note, &[], &strings(&["work"]));
+        assert_eq!(note, "---\ndraft: true\ntags: [\"to do\"]\n---\nBody\n");
+        assert_eq!(tags, ["to do"]);
+    }
+
+    #[test]
+    fn retag_ignores_unterminated_front_matter() {
+        let (note, tags) = retag("---\nnot front-matter\n", &strings(&["a"]), &[]);
+        assert_eq!(note, "---\ntags: [\"a\"]\n---\n\n---\nnot front-matter\n");
+        assert_eq!(tags, ["a"]);
+    }
+
+    #[test]
+    fn with_defaults_keeps_existing_fields() {
+        let fields = [("title", "\"Default\"".to_owned()), ("date", "2000-01-01T00:00:00Z".to_owned())];
+        assert_eq!(
+            with_defaults("---\ntitle: Mine\n---\nBody\n", &fields),
+            "---\ndate: 2000-01-01T00:00:00Z\ntitle: Mine\n---\nBody\n"
+        );
+        assert_eq!(with_defaults("Body\n", &fields[..1]), "---\ntitle: \"Default\"\n---\n\nBody\n");
+    }
+}
@@ -1,17 +1,27 @@
 use std::{
-    io, collections::HashMap
+    fmt, io, collections::HashMap, path::Path, time::{SystemTime, UNIX_EPOCH}
 };
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use ring::digest::{SHA256, digest};
 
-type TlsReader = tokio::io::ReadHalf<tokio_rustls::server::TlsStream<tokio::net::TcpStream>>;
-type TlsWriter = tokio::io::WriteHalf<tokio_rustls::server::TlsStream<tokio::net::TcpStream>>;
+use super::config::ServerConfig;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the chunks a streamed response body is written in.
+const STREAM_CHUNK_SIZE: usize = 16 * 1024;
+/// Maximum size of the request line and headers.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
 
 /// HTTP response builder.
 pub(crate) struct HttpResponse {
     status: String,
-    content: Option<String>,
+    content: Option<Vec<u8>>,
     content_type: String,
+    headers: Vec<(String, String)>,
+    /// Send the content with `Transfer-Encoding: chunked` instead of a `Content-Length`.
+    chunked: bool,
 }
 
 impl HttpResponse {
@@ -21,6 +31,74 @@ impl HttpResponse {
             status: "HTTP/1.1 200 OK\r\n".to_owned(),
             content: None,
             content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 201 Created response.
+    pub fn created() -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 201 Created\r\n".to_owned(),
+            content: None,
+            content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 204 No Content response.
+    pub fn no_content() -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 204 No Content\r\n".to_owned(),
+            content: None,
+            content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 207 Multi-Status response.
+    pub fn multi_status() -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 207 Multi-Status\r\n".to_owned(),
+            content: None,
+            content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 301 Moved Permanently response.
+    pub fn moved_permanently(location: &str) -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 301 Moved Permanently\r\n".to_owned(),
+            content: None,
+            content_type: "text/plain".to_owned(),
+            headers: vec![("Location".to_owned(), location.to_owned())],
+            chunked: false,
+        }
+    }
+
+    /// Create a new 304 Not Modified response.
+    pub fn not_modified() -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 304 Not Modified\r\n".to_owned(),
+            content: None,
+            content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 403 Forbidden response.
+    pub fn forbidden(context: &str) -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 403 Forbidden\r\n".to_owned(),
+            content: Some(format!("403 Forbidden\r\n\r\n{}", context).into_bytes()),
+            content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
         }
     }
 
@@ -28,18 +106,65 @@ impl HttpResponse {
     pub fn not_found() -> Self {
         HttpResponse {
             status: "HTTP/1.1 404 Not Found\r\n".to_owned(),
-            content: Some("404 Not Found".to_owned()),
+            content: Some("404 Not Found".into()),
             content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 405 Method Not Allowed response, listing the methods that are allowed.
+    pub fn method_not_allowed(allowed: &[&str]) -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 405 Method Not Allowed\r\n".to_owned(),
+            content: Some("405 Method Not Allowed".into()),
+            content_type: "text/plain".to_owned(),
+            headers: vec![("Allow".to_owned(), allowed.join(", "))],
+            chunked: false,
+        }
+    }
+
+    /// Create a new 409 Conflict response.
+    pub fn conflict(context: &str) -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 409 Conflict\r\n".to_owned(),
+            content: Some(format!("409 Conflict\r\n\r\n{}", context).into_bytes()),
+            content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 410 Gone response.
+    pub fn gone() -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 410 Gone\r\n".to_owned(),
+            content: Some("410 Gone".into()),
+            content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
         }
     }
 
     /// Create a new 500 Internal Server Error response.
-    #[allow(dead_code)]
     pub fn err() -> Self {
         HttpResponse {
             status: "HTTP/1.1 500 Internal Server Error\r\n".to_owned(),
-            content: Some("500 Internal Server Error".to_owned()),
+            content: Some("500 Internal Server Error".into()),
             content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 400 Bad Request response.
+    pub fn bad_request(context: &str) -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 400 Bad Request\r\n".to_owned(),
+            content: Some(format!("400 Bad Request\r\n\r\n{}", context).into_bytes()),
+            content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
         }
     }
 
@@ -47,8 +172,87 @@ impl HttpResponse {
     pub fn unauth() -> Self {
         HttpResponse {
             status: "HTTP/1.1 401 Unauthorized\r\n".to_owned(),
-            content: Some("401 Unauthorized".to_owned()),
+            content: Some("401 Unauthorized".into()),
+            content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 412 Precondition Failed response.
+    pub fn precondition_failed() -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 412 Precondition Failed\r\n".to_owned(),
+            content: Some("412 Precondition Failed".into()),
+            content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 411 Length Required response.
+    pub fn length_required() -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 411 Length Required\r\n".to_owned(),
+            content: Some("411 Length Required".into()),
+            content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 413 Payload Too Large response.
+    pub fn payload_too_large() -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 413 Payload Too Large\r\n".to_owned(),
+            content: Some("413 Payload Too Large".into()),
+            content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 414 URI Too Long response.
+    pub fn uri_too_long() -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 414 URI Too Long\r\n".to_owned(),
+            content: Some("414 URI Too Long".into()),
             content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 416 Range Not Satisfiable response, telling the client the current length.
+    pub fn range_not_satisfiable(len: u64) -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 416 Range Not Satisfiable\r\n".to_owned(),
+            content: Some("416 Range Not Satisfiable".into()),
+            content_type: "text/plain".to_owned(),
+            headers: vec![("Content-Range".to_owned(), format!("bytes */{len}"))],
+            chunked: false,
+        }
+    }
+
+    /// Create a new 501 Not Implemented response.
+    pub fn not_implemented() -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 501 Not Implemented\r\n".to_owned(),
+            content: Some("501 Not Implemented".into()),
+            content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    /// Create a new 503 Service Unavailable response.
+    pub fn unavailable(retry_after: u64) -> Self {
+        HttpResponse {
+            status: "HTTP/1.1 503 Service Unavailable\r\n".to_owned(),
+            content: Some("503 Service Unavailable".into()),
+            content_type: "text/plain".to_owned(),
+            headers: vec![("Retry-After".to_owned(), retry_after.to_string())],
+            chunked: false,
         }
     }
 
@@ -56,120 +260,334 @@ impl HttpResponse {
     pub fn err_with_context(context: &str) -> Self {
         HttpResponse {
             status: "HTTP/1.1 500 Internal Server Error\r\n".to_owned(),
-            content: Some(format!("500 Internal Server Error\r\n\r\n{}", context)),
+            content: Some(format!("500 Internal Server Error\r\n\r\n{}", context).into_bytes()),
             content_type: "text/plain".to_owned(),
+            headers: Vec::new(),
+            chunked: false,
         }
     }
 
+    /// Get the numeric status code of the response.
+    pub fn status_code(&self) -> u16 {
+        self.status.split(' ').nth(1).and_then(|code| code.parse().ok()).unwrap_or(0)
+    }
+
+    /// Get the content of the response.
+    pub fn content(&self) -> Option<&[u8]> {
+        self.content.as_deref()
+    }
+
+    /// Get the context an error response was created with, which follows its status in the body.
+    pub fn context(&self) -> &str {
+        self.content.as_deref()
+            .and_then(|content| std::str::from_utf8(content).ok())
+            .and_then(|content| content.split_once("\r\n\r\n"))
+            .map_or("", |(_, context)| context)
+    }
+
+    /// Set the content type of the response.
+    pub fn content_type(&mut self, content_type: &str) -> &Self {
+        self.content_type = content_type.to_owned();
+        self
+    }
+
+    /// Add a header to the response.
+    pub fn header(&mut self, name: &str, value: &str) -> &Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Send the content in chunks, for bodies whose length shouldn't have to be known up front.
+    pub fn chunked(&mut self) -> &Self {
+        self.chunked = true;
+        self
+    }
+
     /// Add text content to the response.
     pub fn text(&mut self, content: &str) -> &Self {
-        self.content = Some(content.to_owned());
+        self.content = Some(content.as_bytes().to_vec());
+        self
+    }
+
+    /// Add binary content to the response.
+    pub fn bytes(&mut self, content: &[u8]) -> &Self {
+        self.content = Some(content.to_vec());
         self
     }
 
     /// Add html content to the response.
-    #[allow(dead_code)]
     pub fn html(&mut self, content: &str) -> &Self {
-        self.content = Some(content.to_owned());
+        self.content = Some(content.as_bytes().to_vec());
         self.content_type = "text/html".to_owned();
         self
     }
 
+    /// Add xml content to the response.
+    pub fn xml(&mut self, content: &str) -> &Self {
+        self.content = Some(content.as_bytes().to_vec());
+        self.content_type = "application/xml; charset=utf-8".to_owned();
+        self
+    }
+
     /// Add json content to the response.
     pub fn json(&mut self, content: &str) -> &Self {
-        self.content = Some(content.to_owned());
+        self.content = Some(content.as_bytes().to_vec());
         self.content_type = "application/json".to_owned();
         self
     }
 
-    /// Send the HTTP response over Tcp.
-    pub async fn send(&self, stream: &mut TlsWriter) -> io::Result<()> {
-        let mut response = self.status.clone();
+    /// Add a `Digest` header with the SHA-256 of the content, so clients can verify it arrived intact.
+    pub fn digest(&mut self) -> &Self {
+        let hash = digest(&SHA256, self.content.as_deref().unwrap_or_default());
+        self.header("Digest", &format!("sha-256={}", STANDARD.encode(hash)));
+        self
+    }
+
+    /// Allow browsers on some origins to read the response, `*` allows any origin.
+    pub fn allow_origin(&mut self, origin: Option<&str>, allowed: &[String]) -> &Self {
+        if allowed.iter().any(|allowed| allowed == "*") {
+            self.header("Access-Control-Allow-Origin", "*");
+            return self;
+        }
+
+        // Echo back the origin if it's allowed, and let caches know the answer depends on it.
+        if let Some(origin) = origin.filter(|origin| allowed.iter().any(|allowed| allowed == origin)) {
+            self.header("Access-Control-Allow-Origin", origin);
+        }
+        self.header("Vary", "Origin");
+        self
+    }
+
+    /// Build the status line and headers of the response.
+    fn head(&self) -> String {
+        let mut head = self.status.clone();
+
+        // Custom headers go after the standard ones.
+        let headers: String = self.headers.iter()
+            .map(|(name, value)| format!("{name}: {value}\r\n"))
+            .collect();
 
         if let Some(content) = &self.content {
-            response.push_str(
+            let length = if self.chunked {
+                "Transfer-Encoding: chunked".to_owned()
+            } else {
+                format!("Content-Length: {}", content.len())
+            };
+
+            head.push_str(
                 format!(
-                    "Server: {}\r\nContent-Length: {}\r\nContent-Type: {}\r\nAccess-Control-Allow-Origin: *\r\n\r\n{}",
+                    "Server: {}\r\nDate: {}\r\n{}\r\nContent-Type: {}\r\n{}\r\n",
                     "mxcop@note-server",
-                    content.len(),
+                    http_date(SystemTime::now()),
+                    length,
                     self.content_type,
-                    content
+                    headers
                 )
                 .as_str(),
             );
         } else {
-            response.push_str("Server: mxcop@note-server\r\nContent-Length: 0\r\nAccess-Control-Allow-Origin: *\r\n\r\n");
+            head.push_str(&format!(
+                "Server: mxcop@note-server\r\nDate: {}\r\nContent-Length: 0\r\n{headers}\r\n",
+                http_date(SystemTime::now())
+            ));
+        }
+        head
+    }
+
+    /// Send the HTTP response over a stream, in a single write.
+    pub async fn send<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> io::Result<()> {
+        let mut response = self.head().into_bytes();
+
+        if let Some(content) = &self.content {
+            if self.chunked {
+                for chunk in content.chunks(STREAM_CHUNK_SIZE) {
+                    response.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                    response.extend_from_slice(chunk);
+                    response.extend_from_slice(b"\r\n");
+                }
+                response.extend_from_slice(b"0\r\n\r\n");
+            } else {
+                response.extend_from_slice(content);
+            }
+        }
+
+        stream.write_all(&response).await?;
+        stream.flush().await
+    }
+
+    /// Send only the status line and headers of the response, as an answer to a HEAD request.
+    pub async fn send_head<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> io::Result<()> {
+        stream.write_all(self.head().as_bytes()).await?;
+        stream.flush().await
+    }
+
+    /// Send the HTTP response over a stream, writing the headers first and then streaming the body.
+    pub async fn send_streamed<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> io::Result<()> {
+        stream.write_all(self.head().as_bytes()).await?;
+
+        if let Some(content) = &self.content {
+            for chunk in content.chunks(STREAM_CHUNK_SIZE) {
+                if self.chunked {
+                    stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+                }
+                stream.write_all(chunk).await?;
+                if self.chunked {
+                    stream.write_all(b"\r\n").await?;
+                }
+            }
+            if self.chunked {
+                stream.write_all(b"0\r\n\r\n").await?;
+            }
         }
 
-        stream.write_all(response.as_bytes()).await?;
         stream.flush().await
     }
 }
 
 #[derive(Debug, Default)]
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum RequestType {
     #[default] 
     UNKNOWN, 
     GET, 
+    HEAD,
     POST, 
-    DELETE
+    DELETE,
+    PROPFIND,
+    OPTIONS,
+    TRACE
 }
 
-/// HTTP response builder.
+/// Error for request targets that are longer than allowed.
+#[derive(Debug)]
+pub(crate) struct UriTooLong;
+
+impl fmt::Display for UriTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Request target is too long")
+    }
+}
+
+impl std::error::Error for UriTooLong {}
+
+/// Error for chunked request bodies, which aren't decoded so they need a Content-Length instead.
+#[derive(Debug)]
+pub(crate) struct LengthRequired;
+
+impl fmt::Display for LengthRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Request body needs a Content-Length")
+    }
+}
+
+impl std::error::Error for LengthRequired {}
+
+/// Error for request bodies that are larger than allowed.
+#[derive(Debug)]
+pub(crate) struct PayloadTooLarge;
+
+impl fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Request body is too large")
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+/// Limits on the size of requests, to keep clients from exhausting memory.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RequestLimits {
+    pub max_path: usize,
+    pub max_body: u64,
+}
+
+impl RequestLimits {
+    /// Get the request limits from the server configuration.
+    pub fn new(config: &ServerConfig) -> Self {
+        Self { max_path: config.max_path_length, max_body: config.max_body_size }
+    }
+}
+
+/// Find the length of the request line and headers, including the empty line ending them.
+fn head_len(buf: &[u8]) -> Option<usize> {
+    let lf = buf.windows(2).position(|w| w == b"\n\n").map(|i| i + 2);
+    let crlf = buf.windows(3).position(|w| w == b"\n\r\n").map(|i| i + 3);
+
+    lf.into_iter().chain(crlf).min()
+}
+
+/// HTTP request.
 #[derive(Debug, Default)]
 pub(crate) struct HttpRequest {
     pub req_type: RequestType,
+    /// The path of the request target, without the query string.
     pub path: String,
+    /// The percent-decoded parameters of the query string.
+    pub query: HashMap<String, String>,
+    /// The request line and headers, as received.
+    pub head: String,
     pub headers: HashMap<String, String>,
-    pub body: String,
+    pub body: Vec<u8>,
 }
 
 impl HttpRequest {
-    pub async fn parse(stream: &mut TlsReader) -> io::Result<Self> {
-        // Store all the bytes for our received String
+    /// Read a request from a stream, rejecting requests that exceed the limits.
+    pub async fn parse<R: AsyncRead + Unpin>(stream: &mut R, limits: RequestLimits) -> io::Result<Self> {
+        // Read from the stream until we've received all the headers:
         let mut buf: Vec<u8> = vec![];
-
-        // Read all bytes from the TCP stream:
         let mut rx_bytes = [0u8; 256];
-        loop {
-            let bytes_read = stream.read(&mut rx_bytes).await?;
-
-            buf.extend_from_slice(&rx_bytes[..bytes_read]);
+        let head_len = loop {
+            if let Some(len) = head_len(&buf) {
+                break len;
+            }
+            if buf.len() > MAX_HEAD_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "HTTP request headers are too large"));
+            }
 
-            if bytes_read < 256 {
-                break;
+            let bytes_read = stream.read(&mut rx_bytes).await?;
+            if bytes_read == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed before the end of the headers"));
             }
-        }
-        let buf_len = buf.len();
+            buf.extend_from_slice(&rx_bytes[..bytes_read]);
+        };
+        let mut body = buf.split_off(head_len);
 
         // Check if the content is in UTF8.
-        let Ok(content) = String::from_utf8(buf.to_vec()) else {
+        let Ok(content) = String::from_utf8(buf) else {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "HTTP request doesn't contain valid UTF8"));
         };
 
-        let mut offset = 0;
         let mut request = Self::default();
         let mut first_line = true;
+        let mut content_length: Option<&str> = None;
+        let mut chunked = false;
 
         for line in content.split('\n') {
             // Parse the first line:  "GET /home.html HTTP/1.1"
             if first_line {
                 request.req_type = match line {
                     s if s.starts_with("GET") => RequestType::GET,
+                    s if s.starts_with("HEAD") => RequestType::HEAD,
                     s if s.starts_with("POST") => RequestType::POST,
                     s if s.starts_with("DELETE") => RequestType::DELETE,
+                    s if s.starts_with("PROPFIND") => RequestType::PROPFIND,
+                    s if s.starts_with("OPTIONS") => RequestType::OPTIONS,
+                    s if s.starts_with("TRACE") => RequestType::TRACE,
                     _ => RequestType::UNKNOWN
                 };
                 let mut parts = line.split(' ');
                 parts.next();
-                request.path = parts.next().unwrap_or("/").to_owned();
+                let path = parts.next().unwrap_or("/");
+                if path.len() > limits.max_path {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, UriTooLong));
+                }
+                let (path, query) = path.split_once('?').unwrap_or((path, ""));
+                request.path = path.to_owned();
+                request.query = parse_query(query);
 
                 first_line = false;
             }
 
-            // Count the offset until we reach the body:
-            offset += line.len() + 1;
-
             if line.len() <= 1 {
                 break;
             }
@@ -178,12 +596,469 @@ impl HttpRequest {
             let Some(header) = line.split_once(':') else {
                 continue;
             };
-            request.headers.insert(header.0.to_owned(), header.1.to_owned());
+
+            // Conflicting body lengths are a request smuggling risk:
+            if header.0.eq_ignore_ascii_case("Content-Length") {
+                if content_length.is_some_and(|length| length != header.1.trim()) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Conflicting Content-Length headers"));
+                }
+                content_length = Some(header.1.trim());
+            }
+            if header.0.eq_ignore_ascii_case("Transfer-Encoding") && header.1.to_ascii_lowercase().contains("chunked") {
+                chunked = true;
+            }
+
+            // Header names are case-insensitive, so they are stored in lowercase.
+            request.headers.insert(header.0.trim().to_ascii_lowercase(), header.1.to_owned());
+        }
+
+        if chunked && content_length.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Both Content-Length and Transfer-Encoding: chunked are set"));
+        }
+        // Chunked bodies aren't decoded.
+        if chunked {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, LengthRequired));
+        }
+
+        // Keep reading until the whole body has been received, without a length there is no body.
+        let length = match content_length.map(str::parse::<u64>) {
+            Some(Ok(length)) => length,
+            Some(Err(_)) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid Content-Length header")),
+            None => 0,
+        };
+        if length > limits.max_body {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, PayloadTooLarge));
+        }
+        if (body.len() as u64) < length {
+            let remaining = length - body.len() as u64;
+            stream.take(remaining).read_to_end(&mut body).await?;
+        }
+        if (body.len() as u64) < length {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed before the end of the body"));
         }
+        body.truncate(length as usize);
 
-        // Grab the body from the request.
-        request.body = String::from_utf8(buf[offset..buf_len].to_vec()).unwrap();
+        request.head = content;
+        request.body = body;
 
         Ok(request)
     }
 }
+
+impl HttpRequest {
+    /// Get the trimmed value of a header, the name is matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(|value| value.trim())
+    }
+
+    /// Check if this is a CORS preflight request of a browser.
+    pub fn is_preflight(&self) -> bool {
+        matches!(self.req_type, RequestType::OPTIONS) && self.header("Access-Control-Request-Method").is_some()
+    }
+
+    /// Get the value of a query string parameter.
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.query.get(key).map(String::as_str)
+    }
+
+    /// Pick the offered content type the client prefers, the first offer wins ties and a missing `Accept`.
+    pub fn negotiate<'a>(&self, offers: &[&'a str]) -> Option<&'a str> {
+        negotiate(self.header("Accept"), offers)
+    }
+
+    /// The request line and headers, with the values of the auth header, `Authorization` and cookies replaced by `***`.
+    pub fn redacted_head(&self, auth_header: &str) -> String {
+        let mut redacted = String::with_capacity(self.head.len());
+
+        for line in self.head.split_inclusive('\n') {
+            match line.split_once(':') {
+                Some((name, _)) if [auth_header, "Authorization", "Cookie", "Set-Cookie"].iter().any(|secret| name.trim().eq_ignore_ascii_case(secret)) => {
+                    redacted.push_str(name);
+                    redacted.push_str(": ***\r\n");
+                }
+                _ => redacted.push_str(line),
+            }
+        }
+        redacted
+    }
+}
+
+/// Pick the best offered content type for an `Accept` header, weighing its media ranges by their q-values.
+pub(crate) fn negotiate<'a>(accept: Option<&str>, offers: &[&'a str]) -> Option<&'a str> {
+    let Some(accept) = accept.filter(|accept| !accept.trim().is_empty()) else {
+        return offers.first().copied();
+    };
+
+    // Parse the media ranges, e.g. `text/html;q=0.9`:
+    let ranges: Vec<(&str, f32)> = accept.split(',').filter_map(|range| {
+        let mut params = range.split(';');
+        let media = params.next()?.trim();
+        let quality = match params.find_map(|param| param.trim().strip_prefix("q=")) {
+            Some(quality) => quality.trim().parse::<f32>().ok()?,
+            None => 1.0,
+        };
+        Some((media, quality))
+    }).collect();
+
+    let mut best = None;
+    let mut best_quality = 0.0;
+    for offer in offers {
+        // The most specific range matching an offer decides its quality.
+        let quality = ranges.iter()
+            .filter_map(|(media, quality)| media_specificity(media, offer).map(|specificity| (specificity, *quality)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, quality)| quality);
+
+        if quality > best_quality {
+            best = Some(*offer);
+            best_quality = quality;
+        }
+    }
+    best
+}
+
+/// How specifically a media range (e.g. `text/*`) matches a content type, if it matches at all.
+fn media_specificity(range: &str, content_type: &str) -> Option<u8> {
+    if range.eq_ignore_ascii_case(content_type) {
+        return Some(2);
+    }
+    if range == "*/*" {
+        return Some(0);
+    }
+
+    let kind = range.strip_suffix("/*")?;
+    let matches = content_type.split_once('/').is_some_and(|(offered, _)| offered.eq_ignore_ascii_case(kind));
+    matches.then_some(1)
+}
+
+/// Format a time as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub(crate) fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    let (year, month, day) = civil_date(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[((days + 4) % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Convert days since the epoch to a `(year, month, day)` civil date (see: http://howardhinnant.github.io/date_algorithms.html).
+pub(crate) fn civil_date(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let (era, doe) = (z / 146097, z % 146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    (year, month, day)
+}
+
+/// Get the content type of a note from its file extension, defaulting to plain text.
+pub(crate) fn mime_type(path: &Path) -> &'static str {
+    let extension = path.extension().map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("md" | "markdown") => "text/markdown",
+        Some("html" | "htm") => "text/html",
+        Some("css") => "text/css",
+        Some("csv") => "text/csv",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("yaml" | "yml") => "application/yaml",
+        Some("toml") => "application/toml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("pdf") => "application/pdf",
+        _ => "text/plain",
+    }
+}
+
+/// Add a UTF-8 charset parameter to a text content type, unless it already has a charset.
+pub(crate) fn with_charset(content_type: &str) -> String {
+    if !content_type.starts_with("text/") || content_type.to_ascii_lowercase().contains("charset=") {
+        return content_type.to_owned();
+    }
+    format!("{content_type}; charset=utf-8")
+}
+
+/// Percent-encode a path, leaving unreserved characters and '/' as is.
+pub(crate) fn percent_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Parse a query string (e.g. `start=0&end=10`) into its decoded parameters, the first of repeated keys wins.
+pub(crate) fn parse_query(query: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+        // Spaces may be encoded as `+` in query strings.
+        params.entry(percent_decode(&key.replace('+', " ")))
+            .or_insert_with(|| percent_decode(&value.replace('+', " ")));
+    }
+    params
+}
+
+/// Decode percent-encoded bytes in a path, e.g. `%20` to a space.
+pub(crate) fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: RequestLimits = RequestLimits { max_path: 64, max_body: 16 };
+
+    async fn parse(raw: &str) -> io::Result<HttpRequest> {
+        HttpRequest::parse(&mut raw.as_bytes(), LIMITS).await
+    }
+
+    /// Check that a request was rejected with a typed error, e.g. `UriTooLong`.
+    fn is_rejected_with<T: std::error::Error + 'static>(result: io::Result<HttpRequest>) -> bool {
+        result.is_err_and(|err| err.kind() == io::ErrorKind::InvalidData && err.get_ref().is_some_and(|inner| inner.is::<T>()))
+    }
+
+    #[tokio::test]
+    async fn parse_reads_body_up_to_content_length() {
+        let request = parse("POST /notes/a.md?x=1 HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello, extra").await.unwrap();
+        assert!(matches!(request.req_type, RequestType::POST));
+        assert_eq!(request.path, "/notes/a.md");
+        assert_eq!(request.param("x"), Some("1"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn parse_looks_up_headers_in_any_case() {
+        let request = parse("POST /notes/a.md HTTP/1.1\r\naUtHoRiZaTiOn: secret\r\nCONTENT-LENGTH: 2\r\n\r\nhi").await.unwrap();
+        assert_eq!(request.body, b"hi");
+        assert_eq!(request.header("Authorization"), Some("secret"));
+        assert_eq!(request.header("authorization"), Some("secret"));
+        assert_eq!(request.header("Content-Length"), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn parse_accepts_duplicate_equal_lengths() {
+        let request = parse("POST / HTTP/1.1\r\nContent-Length: 2\r\ncontent-length: 2\r\n\r\nhi").await.unwrap();
+        assert_eq!(request.body, b"hi");
+    }
+
+    #[tokio::test]
+    async fn parse_rejects_conflicting_lengths() {
+        let result = parse("POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\nhi!").await;
+        assert!(result.is_err_and(|err| err.kind() == io::ErrorKind::InvalidData));
+
+        let result = parse("POST / HTTP/1.1\r\nContent-Length: 2\r\nTransfer-Encoding: chunked\r\n\r\nhi").await;
+        assert!(result.is_err_and(|err| err.kind() == io::ErrorKind::InvalidData));
+
+        let result = parse("POST / HTTP/1.1\r\nContent-Length: two\r\n\r\nhi").await;
+        assert!(result.is_err_and(|err| err.kind() == io::ErrorKind::InvalidData));
+    }
+
+    #[tokio::test]
+    async fn parse_requires_length_of_chunked_bodies() {
+        assert!(is_rejected_with::<LengthRequired>(parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhi\r\n0\r\n\r\n").await));
+        assert!(parse("POST / HTTP/1.1\r\n\r\n").await.is_ok_and(|request| request.body.is_empty()));
+        assert!(parse("GET / HTTP/1.1\r\n\r\n").await.is_ok_and(|request| request.body.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn parse_rejects_large_bodies() {
+        assert!(is_rejected_with::<PayloadTooLarge>(parse("POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n").await));
+        assert!(parse("POST / HTTP/1.1\r\nContent-Length: 16\r\n\r\n0123456789abcdef").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn parse_rejects_long_targets() {
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64));
+        assert!(is_rejected_with::<UriTooLong>(parse(&long).await));
+
+        let short = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(63));
+        assert!(parse(&short).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn parse_rejects_truncated_bodies() {
+        let result = parse("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhi").await;
+        assert!(result.is_err_and(|err| err.kind() == io::ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn http_date_formats_imf_fixdate() {
+        use std::time::Duration;
+
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(UNIX_EPOCH + Duration::from_secs(784111777)), "Sun, 06 Nov 1994 08:49:37 GMT");
+        // A leap day, and the day after it.
+        assert_eq!(http_date(UNIX_EPOCH + Duration::from_secs(951782400)), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(http_date(UNIX_EPOCH + Duration::from_secs(951868799)), "Tue, 29 Feb 2000 23:59:59 GMT");
+        assert_eq!(http_date(UNIX_EPOCH + Duration::from_secs(951868800)), "Wed, 01 Mar 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn parse_query_decodes_pairs() {
+        let query = parse_query("start=0&end=10&name=a%20b+c&flag&&start=5");
+        assert_eq!(query.get("start").map(String::as_str), Some("0"));
+        assert_eq!(query.get("end").map(String::as_str), Some("10"));
+        assert_eq!(query.get("name").map(String::as_str), Some("a b c"));
+        assert_eq!(query.get("flag").map(String::as_str), Some(""));
+        assert_eq!(query.len(), 4);
+        assert!(parse_query("").is_empty());
+    }
+
+    #[test]
+    fn percent_decode_handles_bad_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        assert_eq!(percent_decode("%e2%9c%93"), "\u{2713}");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("a+b"), "a+b");
+        assert_eq!(percent_decode("%ff"), "\u{fffd}");
+    }
+
+    #[test]
+    fn negotiate_weighs_q_values() {
+        let offers = ["text/plain", "text/html"];
+        assert_eq!(negotiate(None, &offers), Some("text/plain"));
+        assert_eq!(negotiate(Some(" "), &offers), Some("text/plain"));
+        assert_eq!(negotiate(Some("text/html"), &offers), Some("text/html"));
+        assert_eq!(negotiate(Some("text/html;q=0.5, text/plain;q=0.9"), &offers), Some("text/plain"));
+        assert_eq!(negotiate(Some("text/html, */*;q=0.1"), &offers), Some("text/html"));
+        assert_eq!(negotiate(Some("*/*"), &offers), Some("text/plain"));
+        assert_eq!(negotiate(Some("application/json"), &offers), None);
+    }
+
+    #[test]
+    fn negotiate_prefers_specific_ranges() {
+        let offers = ["text/plain", "text/html"];
+        // `text/plain;q=0` rules out plain text, even though `text/*` would allow it.
+        assert_eq!(negotiate(Some("text/*, text/plain;q=0"), &offers), Some("text/html"));
+        assert_eq!(negotiate(Some("TEXT/HTML"), &offers), Some("text/html"));
+        assert_eq!(negotiate(Some("text/html;q=0"), &["text/html"]), None);
+    }
+
+    #[test]
+    fn mime_type_maps_extensions() {
+        let table = [
+            ("note.md", "text/markdown"),
+            ("note.MARKDOWN", "text/markdown"),
+            ("page.htm", "text/html"),
+            ("data.csv", "text/csv"),
+            ("app.js", "text/javascript"),
+            ("data.json", "application/json"),
+            ("conf.yml", "application/yaml"),
+            ("conf.toml", "application/toml"),
+            ("logo.svg", "image/svg+xml"),
+            ("photo.JPG", "image/jpeg"),
+            ("doc.pdf", "application/pdf"),
+            ("notes.txt", "text/plain"),
+            ("Makefile", "text/plain"),
+            ("archive.tar.gz", "text/plain"),
+        ];
+        for (name, content_type) in table {
+            assert_eq!(mime_type(Path::new(name)), content_type, "{name}");
+        }
+    }
+
+    #[test]
+    fn with_charset_only_changes_text() {
+        assert_eq!(with_charset("text/markdown"), "text/markdown; charset=utf-8");
+        assert_eq!(with_charset("text/html; charset=latin1"), "text/html; charset=latin1");
+        assert_eq!(with_charset("image/png"), "image/png");
+    }
+
+    #[tokio::test]
+    async fn redacted_head_hides_credentials() {
+        let request = parse("GET /notes HTTP/1.1\r\nx-pyne-token: secret-token\r\nAuthorization: Basic c2VjcmV0\r\nCOOKIE: session=secret-cookie\r\nAccept: text/plain\r\n\r\n").await.unwrap();
+        let redacted = request.redacted_head("X-Pyne-Token");
+
+        assert_eq!(redacted, "GET /notes HTTP/1.1\r\nx-pyne-token: ***\r\nAuthorization: ***\r\nCOOKIE: ***\r\nAccept: text/plain\r\n\r\n");
+        assert!(!redacted.contains("secret") && !redacted.contains("c2VjcmV0"));
+    }
+
+    #[tokio::test]
+    async fn chunked_responses_reassemble_to_the_content() {
+        let content: String = (0..STREAM_CHUNK_SIZE * 2 + 100).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+        let mut response = HttpResponse::ok();
+        response.text(&content);
+        response.chunked();
+
+        let mut sent = Vec::new();
+        response.send(&mut sent).await.unwrap();
+        let end = sent.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+        let head = std::str::from_utf8(&sent[..end]).unwrap();
+        assert!(head.contains("\r\nTransfer-Encoding: chunked\r\n") && !head.contains("Content-Length"));
+
+        // The body ends with the zero chunk, so the next response could follow it.
+        let body = &sent[end + 4..];
+        assert!(body.starts_with(format!("{STREAM_CHUNK_SIZE:x}\r\n").as_bytes()));
+        assert!(body.ends_with(b"\r\n0\r\n\r\n"));
+        assert_eq!(crate::run::testing::dechunk(body), content.as_bytes());
+    }
+
+    #[test]
+    fn allow_origin_echoes_only_allowed_origins() {
+        let headers = |origin: Option<&str>, allowed: &[&str]| {
+            let allowed: Vec<String> = allowed.iter().map(|&allowed| allowed.to_owned()).collect();
+            let mut response = HttpResponse::ok();
+            response.allow_origin(origin, &allowed);
+            response.headers
+        };
+        let header = |name: &str, value: &str| (name.to_owned(), value.to_owned());
+
+        assert_eq!(headers(Some("https://a.example"), &["*"]), [header("Access-Control-Allow-Origin", "*")]);
+        assert_eq!(
+            headers(Some("https://a.example"), &["https://b.example", "https://a.example"]),
+            [header("Access-Control-Allow-Origin", "https://a.example"), header("Vary", "Origin")]
+        );
+        assert_eq!(headers(Some("https://evil.example"), &["https://a.example"]), [header("Vary", "Origin")]);
+        assert_eq!(headers(None, &["https://a.example"]), [header("Vary", "Origin")]);
+    }
+}
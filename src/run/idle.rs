@@ -0,0 +1,140 @@
+use std::{collections::HashMap, io, pin::Pin, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, task::{Context, Poll}, time::{Duration, Instant}};
+
+use tokio::{io::{AsyncRead, AsyncWrite, ReadBuf}, task::AbortHandle};
+
+/// Open connections and when they last sent or received data, so idle ones can be closed.
+#[derive(Debug)]
+pub(crate) struct IdleTracker {
+    epoch: Instant,
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, Tracked>>,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    /// Milliseconds since the epoch of the tracker.
+    last_active: Arc<AtomicU64>,
+    /// Handle to close the connection, set once its task has been spawned.
+    handle: Option<AbortHandle>,
+}
+
+/// Registration of a connection, which is removed from the tracker when dropped.
+#[derive(Debug)]
+pub(crate) struct Activity {
+    id: u64,
+    epoch: Instant,
+    last_active: Arc<AtomicU64>,
+    tracker: Arc<IdleTracker>,
+}
+
+impl IdleTracker {
+    pub fn new() -> Self {
+        IdleTracker {
+            epoch: Instant::now(),
+            next_id: AtomicU64::new(0),
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Start tracking a new connection, which counts as active from now on.
+    pub fn register(self: &Arc<Self>) -> Activity {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let last_active = Arc::new(AtomicU64::new(self.epoch.elapsed().as_millis() as u64));
+
+        self.lock().insert(id, Tracked { last_active: last_active.clone(), handle: None });
+        Activity { id, epoch: self.epoch, last_active, tracker: self.clone() }
+    }
+
+    /// Set the handle used to close a connection, unless it's already gone.
+    pub fn attach(&self, activity_id: u64, handle: AbortHandle) {
+        if let Some(tracked) = self.lock().get_mut(&activity_id) {
+            tracked.handle = Some(handle);
+        }
+    }
+
+    /// Close the connections that have been idle for longer than the timeout, returning how many.
+    pub fn reap(&self, timeout: Duration) -> u64 {
+        let now = self.epoch.elapsed().as_millis() as u64;
+        let timeout = timeout.as_millis() as u64;
+        let mut reaped = 0;
+
+        for tracked in self.lock().values() {
+            let idle = now.saturating_sub(tracked.last_active.load(Ordering::Relaxed));
+
+            if let (true, Some(handle)) = (idle > timeout, &tracked.handle) {
+                if !handle.is_finished() {
+                    handle.abort();
+                    reaped += 1;
+                }
+            }
+        }
+        reaped
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Tracked>> {
+        // The map stays consistent even if a holder panicked.
+        self.connections.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Activity {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Mark the connection as active right now.
+    fn touch(&self) {
+        self.last_active.store(self.epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Activity {
+    fn drop(&mut self) {
+        self.tracker.lock().remove(&self.id);
+    }
+}
+
+/// Stream that marks its connection as active whenever data is read or written.
+pub(crate) struct TrackedStream<S> {
+    inner: S,
+    activity: Activity,
+}
+
+impl<S> TrackedStream<S> {
+    pub fn new(inner: S, activity: Activity) -> Self {
+        TrackedStream { inner, activity }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TrackedStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if buf.filled().len() > filled {
+            self.activity.touch();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TrackedStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(written)) = poll {
+            if written > 0 {
+                self.activity.touch();
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
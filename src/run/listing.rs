@@ -0,0 +1,316 @@
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, sync::Mutex, thread, time::SystemTime};
+
+use super::{config::ServerConfig, http::{http_date, percent_encode}};
+
+/// A single entry of the notes listing.
+#[derive(Debug, Clone)]
+pub(crate) struct ListEntry {
+    pub name: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    pub symlink: bool,
+}
+
+/// Scan a directory for the entries of the notes listing, statting up to `concurrency` entries at once.
+pub fn scan(dir: &Path, skip_broken_symlinks: bool, concurrency: usize) -> io::Result<Vec<ListEntry>> {
+    let mut found = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        found.push((entry.path(), entry.file_type()?.is_symlink()));
+    }
+
+    // Stat the entries in parallel chunks, which keeps them in directory order.
+    let chunk_size = found.len().div_ceil(concurrency.max(1)).max(1);
+    let metas: Vec<Option<fs::Metadata>> = thread::scope(|scope| {
+        let workers: Vec<_> = found.chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || {
+                // Follow symlinks, so the size and modified time are of the target.
+                chunk.iter().map(|(path, _)| fs::metadata(path).ok()).collect::<Vec<_>>()
+            }))
+            .collect();
+
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    });
+
+    let mut entries = Vec::with_capacity(found.len());
+
+    for ((path, symlink), meta) in found.into_iter().zip(metas) {
+        if symlink && skip_broken_symlinks && meta.is_none() {
+            continue;
+        }
+
+        entries.push(ListEntry {
+            name: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+            size: meta.as_ref().map_or(0, |meta| meta.len()),
+            modified: meta.and_then(|meta| meta.modified().ok()),
+            symlink,
+        });
+    }
+    Ok(entries)
+}
+
+/// In-memory cache of the notes listing, invalidated on writes and deletes.
+#[derive(Debug, Default)]
+pub(crate) struct ListingCache {
+    enabled: bool,
+    skip_broken_symlinks: bool,
+    concurrency: usize,
+    serve_hidden: bool,
+    /// Listings of each directory that has been listed, e.g. of collections.
+    entries: Mutex<HashMap<PathBuf, Vec<ListEntry>>>,
+}
+
+impl ListingCache {
+    /// Create a new listing cache, which does nothing if it isn't enabled.
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            enabled: config.cache_listing,
+            skip_broken_symlinks: config.skip_broken_symlinks,
+            concurrency: config.listing_concurrency,
+            serve_hidden: config.serve_hidden,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the listing of a directory, scanning it on a cache miss.
+    pub fn get(&self, dir: &Path) -> io::Result<Vec<ListEntry>> {
+        if !self.enabled {
+            return self.scan(dir);
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entries) = entries.get(dir) {
+            return Ok(entries.clone());
+        }
+
+        let scanned = self.scan(dir)?;
+        entries.insert(dir.to_owned(), scanned.clone());
+        Ok(scanned)
+    }
+
+    /// Scan a directory, leaving out dotfiles unless they're served.
+    fn scan(&self, dir: &Path) -> io::Result<Vec<ListEntry>> {
+        let mut entries = scan(dir, self.skip_broken_symlinks, self.concurrency)?;
+
+        if !self.serve_hidden {
+            entries.retain(|entry| !entry.name.starts_with('.'));
+        }
+        Ok(entries)
+    }
+
+    /// Drop the cached listings, so the next request scans again.
+    pub fn invalidate(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// Order of the notes listing.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) enum ListSort {
+    #[default]
+    Name,
+    NameDesc,
+    Modified,
+    ModifiedDesc,
+}
+
+impl ListSort {
+    /// Get the order from its name in a query string, a leading `-` makes it descending.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "name" => Some(Self::Name),
+            "-name" => Some(Self::NameDesc),
+            "mtime" => Some(Self::Modified),
+            "-mtime" => Some(Self::ModifiedDesc),
+            _ => None,
+        }
+    }
+
+    /// Get the name of the order, as it's given in a query string.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Name => "name",
+            Self::NameDesc => "-name",
+            Self::Modified => "mtime",
+            Self::ModifiedDesc => "-mtime",
+        }
+    }
+
+    /// Sort the entries, notes modified at the same time are ordered by name.
+    pub fn sort(self, entries: &mut [ListEntry]) {
+        match self {
+            Self::Name => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+            Self::NameDesc => entries.sort_by(|a, b| b.name.cmp(&a.name)),
+            Self::Modified => entries.sort_by(|a, b| a.modified.cmp(&b.modified).then_with(|| a.name.cmp(&b.name))),
+            Self::ModifiedDesc => entries.sort_by(|a, b| b.modified.cmp(&a.modified).then_with(|| a.name.cmp(&b.name))),
+        }
+    }
+}
+
+impl ListEntry {
+    /// Get the entry as it's reported by `/list`.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({ "name": self.name, "symlink": self.symlink })
+    }
+}
+
+/// Escape text for use inside an XML document.
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Render the listing as a WebDAV multistatus document.
+pub fn to_multistatus(entries: &[ListEntry], base: &str) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n");
+
+    for entry in entries {
+        let modified = entry.modified.map(http_date).unwrap_or_default();
+
+        xml.push_str(&format!(
+            "<D:response><D:href>{}/notes/{}</D:href><D:propstat><D:prop>\
+            <D:displayname>{}</D:displayname><D:getcontentlength>{}</D:getcontentlength>\
+            <D:getlastmodified>{}</D:getlastmodified><D:resourcetype/>\
+            </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+            escape_xml(base),
+            escape_xml(&percent_encode(&entry.name)),
+            escape_xml(&entry.name),
+            entry.size,
+            modified
+        ));
+    }
+
+    xml.push_str("</D:multistatus>\n");
+    xml
+}
+
+/// Render a page of the listing as an HTML table, with links to the previous and next pages.
+pub fn to_html(entries: &[&ListEntry], start: u16, end: u16, total: usize, sort: ListSort) -> String {
+    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Notes</title></head>\n<body>\n");
+    html.push_str("<table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n");
+
+    for entry in entries {
+        let modified = entry.modified.map(http_date).unwrap_or_default();
+
+        html.push_str(&format!(
+            "<tr><td><a href=\"/notes/{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            escape_xml(&percent_encode(&entry.name)),
+            escape_xml(&entry.name),
+            entry.size,
+            modified
+        ));
+    }
+    html.push_str("</table>\n<nav>\n");
+
+    // Keep the page size when moving between pages.
+    let size = end - start;
+    if size > 0 && start > 0 {
+        html.push_str(&format!("<a rel=\"prev\" href=\"/list?start={}&amp;end={start}&amp;sort={}\">Previous</a>\n", start.saturating_sub(size), sort.name()));
+    }
+    if size > 0 && (end as usize) < total {
+        html.push_str(&format!("<a rel=\"next\" href=\"/list?start={end}&amp;end={}&amp;sort={}\">Next</a>\n", end.saturating_add(size), sort.name()));
+    }
+
+    html.push_str("</nav>\n</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::testing::TempDir;
+
+    fn names(entries: &[ListEntry]) -> Vec<(&str, bool)> {
+        let mut names: Vec<(&str, bool)> = entries.iter().map(|entry| (entry.name.as_str(), entry.symlink)).collect();
+        names.sort();
+        names
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scan_flags_symlinks() {
+        let dir = TempDir::new("symlinks");
+        fs::write(dir.join("a.md"), "content").unwrap();
+        std::os::unix::fs::symlink(dir.join("a.md"), dir.join("link.md")).unwrap();
+        std::os::unix::fs::symlink(dir.join("missing.md"), dir.join("broken.md")).unwrap();
+
+        let entries = scan(dir.path(), false, 4).unwrap();
+        assert_eq!(names(&entries), [("a.md", false), ("broken.md", true), ("link.md", true)]);
+
+        // Symlinks report their target's size.
+        let link = entries.iter().find(|entry| entry.name == "link.md").unwrap();
+        assert_eq!(link.size, 7);
+        assert_eq!(link.to_json()["symlink"], true);
+
+        assert_eq!(names(&scan(dir.path(), true, 4).unwrap()), [("a.md", false), ("link.md", true)]);
+    }
+
+    #[test]
+    fn concurrent_scan_matches_the_directory() {
+        let dir = TempDir::new("concurrent-scan");
+        for i in 0..25 {
+            fs::write(dir.join(format!("note-{i:02}.md")), "x".repeat(i)).unwrap();
+        }
+
+        let serial = scan(dir.path(), false, 1).unwrap();
+        assert_eq!(serial.len(), 25);
+        for entry in &serial {
+            let i: usize = entry.name["note-".len()..][..2].parse().unwrap();
+            assert_eq!(entry.size, i as u64);
+            assert!(entry.modified.is_some());
+        }
+
+        // Any concurrency gives the same entries, in the same order.
+        let order = |entries: &[ListEntry]| entries.iter().map(|entry| (entry.name.clone(), entry.size)).collect::<Vec<_>>();
+        for concurrency in [0, 2, 7, 25, 64] {
+            assert_eq!(order(&scan(dir.path(), false, concurrency).unwrap()), order(&serial), "concurrency {concurrency}");
+        }
+    }
+
+    #[test]
+    fn html_listing_links_to_the_neighbouring_pages() {
+        let entry = ListEntry { name: "a b.md".to_owned(), size: 12, modified: Some(SystemTime::UNIX_EPOCH), symlink: false };
+
+        let middle = to_html(&[&entry], 10, 20, 35, ListSort::ModifiedDesc);
+        assert!(middle.contains("<a rel=\"prev\" href=\"/list?start=0&amp;end=10&amp;sort=-mtime\">Previous</a>"));
+        assert!(middle.contains("<a rel=\"next\" href=\"/list?start=20&amp;end=30&amp;sort=-mtime\">Next</a>"));
+        assert!(middle.contains("<td><a href=\"/notes/a%20b.md\">a b.md</a></td><td>12</td><td>Thu, 01 Jan 1970 00:00:00 GMT</td>"));
+
+        // The first and last pages only link one way.
+        let first = to_html(&[&entry], 0, 10, 35, ListSort::Name);
+        assert!(!first.contains("rel=\"prev\"") && first.contains("href=\"/list?start=10&amp;end=20&amp;sort=name\""));
+        let last = to_html(&[&entry], 30, 40, 35, ListSort::Name);
+        assert!(last.contains("href=\"/list?start=20&amp;end=30&amp;sort=name\"") && !last.contains("rel=\"next\""));
+    }
+
+    #[test]
+    fn sort_orders_by_name_or_modified_time() {
+        let entry = |name: &str, secs: u64| ListEntry {
+            name: name.to_owned(),
+            size: 0,
+            modified: Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs)),
+            symlink: false,
+        };
+        let sorted = |sort: &str| {
+            let mut entries = vec![entry("b.md", 30), entry("d.md", 10), entry("a.md", 20), entry("c.md", 10)];
+            ListSort::parse(sort).unwrap().sort(&mut entries);
+            entries.into_iter().map(|entry| entry.name).collect::<Vec<_>>()
+        };
+
+        assert_eq!(sorted("name"), ["a.md", "b.md", "c.md", "d.md"]);
+        assert_eq!(sorted("-name"), ["d.md", "c.md", "b.md", "a.md"]);
+        // Notes modified at the same time keep a stable order.
+        assert_eq!(sorted("mtime"), ["c.md", "d.md", "a.md", "b.md"]);
+        assert_eq!(sorted("-mtime"), ["b.md", "a.md", "c.md", "d.md"]);
+
+        assert!(matches!(ListSort::default(), ListSort::Name));
+        assert!(ListSort::parse("size").is_none());
+        for name in ["name", "-name", "mtime", "-mtime"] {
+            assert_eq!(ListSort::parse(name).unwrap().name(), name);
+        }
+    }
+}
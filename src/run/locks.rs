@@ -0,0 +1,69 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use tokio::sync::OwnedMutexGuard;
+
+/// Locks of the notes being written, so writes to the same note happen one after the other.
+#[derive(Debug, Default)]
+pub(crate) struct NoteLocks {
+    locks: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl NoteLocks {
+    /// Get the lock of a note, which is shared by everyone writing it.
+    fn get(&self, path: &Path) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.locks.lock().unwrap();
+
+        // Drop the locks nobody is holding or waiting on anymore.
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks.entry(path.to_owned()).or_default().clone()
+    }
+
+    /// Lock a note for writing, blocking until earlier writers of it are done.
+    pub fn lock(&self, path: &Path) -> OwnedMutexGuard<()> {
+        self.get(path).blocking_lock_owned()
+    }
+
+    /// Lock several notes for writing, in sorted order so batches locking the same notes can't deadlock.
+    pub fn lock_all<'a>(&self, paths: impl IntoIterator<Item = &'a Path>) -> Vec<OwnedMutexGuard<()>> {
+        let mut paths: Vec<&Path> = paths.into_iter().collect();
+        paths.sort();
+        paths.dedup();
+
+        paths.into_iter().map(|path| self.lock(path)).collect()
+    }
+
+    /// Lock a note for writing, unless someone else is writing it.
+    pub fn try_lock(&self, path: &Path) -> Option<OwnedMutexGuard<()>> {
+        self.get(path).try_lock_owned().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, thread, time::Duration};
+
+    #[test]
+    fn writers_of_a_note_take_turns() {
+        let locks = Arc::new(NoteLocks::default());
+        let guard = locks.lock(Path::new("a.md"));
+
+        // Writers of other notes aren't held up.
+        assert!(locks.try_lock(Path::new("b.md")).is_some());
+        assert!(locks.try_lock(Path::new("a.md")).is_none());
+
+        let (done, finished) = mpsc::channel();
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || {
+                let _guard = locks.lock(Path::new("a.md"));
+                done.send(()).unwrap();
+            })
+        };
+        assert!(finished.recv_timeout(Duration::from_millis(100)).is_err());
+
+        drop(guard);
+        finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        waiter.join().unwrap();
+    }
+}
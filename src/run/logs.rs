@@ -0,0 +1,121 @@
+use std::{fs, io::{self, Read, Seek, SeekFrom, Write}, path::Path, sync::atomic::{AtomicBool, Ordering}, time::Duration};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::http::HttpResponse;
+
+/// How often a followed log file is checked for new lines.
+const FOLLOW_POLL: Duration = Duration::from_millis(500);
+
+/// Polls without new lines after which a comment is sent, so quiet streams aren't closed as idle.
+const FOLLOW_KEEPALIVE: u32 = 30;
+
+/// Size of the blocks a log file is read backwards in.
+const TAIL_BLOCK_SIZE: u64 = 8 * 1024;
+
+/// Writer sending log records to stderr and a log file.
+struct Tee(fs::File);
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        self.0.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        self.0.flush()
+    }
+}
+
+/// Log to stderr and to the log file if there is one, `RUST_LOG` can change the verbosity.
+pub fn init(file: Option<&Path>) -> io::Result<()> {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+
+    if let Some(file) = file {
+        let file = fs::OpenOptions::new().create(true).append(true).open(file)?;
+        builder.target(env_logger::Target::Pipe(Box::new(Tee(file))));
+    }
+    builder.try_init().map_err(io::Error::other)
+}
+
+/// Read the last lines of a log file, without reading all of it.
+pub fn tail(path: &Path, lines: usize) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut start = len;
+    let mut content = Vec::new();
+
+    // Read blocks from the end until there's one more line than needed, or the file runs out.
+    while start > 0 && content.iter().filter(|&&byte| byte == b'\n').count() <= lines {
+        let size = TAIL_BLOCK_SIZE.min(start);
+        start -= size;
+
+        let mut block = vec![0; size as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut block)?;
+
+        block.extend_from_slice(&content);
+        content = block;
+    }
+
+    let content = String::from_utf8_lossy(&content);
+    let last: Vec<&str> = content.lines().rev().take(lines).collect();
+
+    Ok(last.into_iter().rev().map(|line| format!("{line}\n")).collect())
+}
+
+/// Stream the lines added to a log file as server-sent events, until the client goes away or the server drains.
+pub async fn follow<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(path: &Path, draining: &AtomicBool, reader: &mut R, writer: &mut W) -> io::Result<()> {
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::End(0))?;
+
+    let mut response = HttpResponse::ok();
+    response.text("");
+    response.content_type("text/event-stream");
+    response.header("Cache-Control", "no-cache");
+    response.chunked();
+    response.send_head(writer).await?;
+
+    let mut interval = tokio::time::interval(FOLLOW_POLL);
+    let mut partial = Vec::new();
+    let mut quiet = 0;
+    let mut byte = [0; 1];
+
+    loop {
+        // Stop as soon as the client closes the connection.
+        tokio::select! {
+            _ = interval.tick() => {}
+            read = reader.read(&mut byte) => if read? == 0 {
+                return Ok(());
+            }
+        }
+        // End the stream, so the server can finish draining.
+        if draining.load(Ordering::SeqCst) {
+            writer.write_all(b"0\r\n\r\n").await?;
+            return writer.flush().await;
+        }
+        file.read_to_end(&mut partial)?;
+
+        // Only send whole lines, the rest is kept until it's finished.
+        let mut events = String::new();
+        if let Some(end) = partial.iter().rposition(|&byte| byte == b'\n') {
+            let lines: Vec<u8> = partial.drain(..=end).collect();
+            for line in String::from_utf8_lossy(&lines).lines() {
+                events.push_str(&format!("data: {line}\n\n"));
+            }
+        }
+
+        quiet = if events.is_empty() { quiet + 1 } else { 0 };
+        if quiet >= FOLLOW_KEEPALIVE {
+            events.push_str(": keepalive\n\n");
+            quiet = 0;
+        }
+
+        if !events.is_empty() {
+            writer.write_all(format!("{:x}\r\n{events}\r\n", events.len()).as_bytes()).await?;
+            writer.flush().await?;
+        }
+    }
+}
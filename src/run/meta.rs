@@ -0,0 +1,40 @@
+use std::{fs, io, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+/// Metadata of a note, stored in a sidecar file outside of the notes directory.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub(crate) struct NoteMeta {
+    /// Refuse edits and deletes of the note.
+    pub readonly: bool,
+}
+
+/// Get the sidecar path of a note, which mirrors its path inside the notes directory.
+pub fn sidecar_path(meta_dir: &Path, notes_dir: &Path, note: &Path) -> Option<PathBuf> {
+    let relative = note.strip_prefix(notes_dir).ok()?;
+    let mut name = relative.file_name()?.to_owned();
+    name.push(".json");
+
+    Some(meta_dir.join(relative).with_file_name(name))
+}
+
+impl NoteMeta {
+    /// Load the metadata from a sidecar file, which defaults if it doesn't exist.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Save the metadata to a sidecar file.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)
+    }
+}
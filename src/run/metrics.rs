@@ -0,0 +1,91 @@
+use std::{fs, io, path::Path, sync::atomic::{AtomicU64, Ordering}};
+
+use serde::{Deserialize, Serialize};
+
+/// Request counters shared between all connections.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    requests: AtomicU64,
+    /// Responses per status class (1xx up to 5xx).
+    status: [AtomicU64; 5],
+    /// Idle connections closed by the reaper.
+    reaped: AtomicU64,
+}
+
+/// A point in time copy of the metrics.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub(crate) struct Snapshot {
+    pub requests: u64,
+    pub status: [u64; 5],
+    #[serde(default)]
+    pub reaped: u64,
+}
+
+impl Metrics {
+    /// Record a handled request and the status code of its response.
+    pub fn record(&self, status: u16) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(class) = self.status.get((status / 100).saturating_sub(1) as usize) {
+            class.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Record idle connections that have been closed.
+    pub fn record_reaped(&self, count: u64) {
+        self.reaped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Create metrics continuing from a snapshot.
+    pub fn from_snapshot(snapshot: &Snapshot) -> Self {
+        Metrics {
+            requests: AtomicU64::new(snapshot.requests),
+            status: snapshot.status.map(AtomicU64::new),
+            reaped: AtomicU64::new(snapshot.reaped),
+        }
+    }
+
+    /// Take a snapshot of the current counters.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            requests: self.requests.load(Ordering::Relaxed),
+            status: std::array::from_fn(|i| self.status[i].load(Ordering::Relaxed)),
+            reaped: self.reaped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Take a snapshot of the current counters and zero them.
+    pub fn reset(&self) -> Snapshot {
+        Snapshot {
+            requests: self.requests.swap(0, Ordering::Relaxed),
+            status: std::array::from_fn(|i| self.status[i].swap(0, Ordering::Relaxed)),
+            reaped: self.reaped.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+impl Snapshot {
+    /// Load a snapshot from a file, which defaults if it doesn't exist.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Save the snapshot to a file.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_string(self)?)
+    }
+
+    /// Serialize the snapshot as a JSON object.
+    pub fn to_json(&self) -> String {
+        let status: serde_json::Map<String, serde_json::Value> = self.status.iter().enumerate()
+            .map(|(i, count)| (format!("{}xx", i + 1), (*count).into()))
+            .collect();
+
+        serde_json::json!({ "requests": self.requests, "status": status, "reaped": self.reaped }).to_string()
+    }
+}
@@ -1,177 +1,2866 @@
-use std::{net::ToSocketAddrs, io::{self}, fs, path::PathBuf, sync::Arc};
-use clap::ArgMatches;
-use http::{HttpResponse, HttpRequest, RequestType};
-use tokio::{io::{AsyncWriteExt, split}, net::{TcpListener, TcpStream}};
-use tokio_rustls::{TlsAcceptor, rustls, server::TlsStream};
+use std::{net::{SocketAddr, ToSocketAddrs}, io::{self, Seek, SeekFrom, Write}, fs, path::{Component, Path, PathBuf}, sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use clap::{ArgMatches, parser::ValueSource};
+use serde::Deserialize;
+use cache::NoteCache;
+use config::ServerConfig;
+use export::ExportFormat;
+use idle::{IdleTracker, TrackedStream};
+use http::{HttpResponse, HttpRequest, LengthRequired, PayloadTooLarge, RequestLimits, RequestType, UriTooLong, http_date, mime_type, percent_decode, percent_encode, with_charset};
+use listing::{ListEntry, ListSort, ListingCache};
+use locks::NoteLocks;
+use meta::NoteMeta;
+use rand::distributions::{Alphanumeric, DistString};
+use ring::digest::{Context, SHA256, digest};
+use metrics::{Metrics, Snapshot};
+use tombstones::Tombstones;
+use tokio::{io::{AsyncRead, AsyncWrite, AsyncWriteExt, split}, net::TcpListener, task::JoinSet};
+use tokio_rustls::TlsAcceptor;
 
+mod cache;
+mod config;
+mod export;
+mod frontmatter;
 mod http;
+mod idle;
+mod listing;
+mod locks;
+mod logs;
+mod meta;
+mod metrics;
+mod search;
+#[cfg(test)]
+pub(crate) mod testing;
 mod tls;
+mod tombstones;
+
+/// Methods supported on a single note.
+const NOTE_METHODS: &[&str] = &["GET", "HEAD", "POST", "DELETE", "OPTIONS"];
+/// Methods supported on the notes directory itself.
+const NOTES_DIR_METHODS: &[&str] = &["PROPFIND", "OPTIONS"];
+/// Methods supported anywhere on the server.
+const SERVER_METHODS: &[&str] = &["GET", "HEAD", "POST", "DELETE", "PROPFIND", "OPTIONS"];
+
+/// Seconds browsers may cache the answer to a CORS preflight request.
+const CORS_MAX_AGE: u64 = 86400;
+
+/// Seconds clients are told to wait before retrying while the server is draining.
+const DRAIN_RETRY_AFTER: u64 = 30;
+
+/// Highest suffix tried to make the name of a note unique.
+const MAX_UNIQUE_SUFFIX: usize = 1000;
+
+/// Seconds to wait for open connections to finish when shutting down.
+const SHUTDOWN_TIMEOUT: u64 = 10;
+
+/// State shared between all connections of a running server.
+pub(crate) struct State {
+    pub config: ServerConfig,
+    pub notes: PathBuf,
+    /// Directory with the sidecar metadata of notes.
+    pub meta: PathBuf,
+    pub auth: String,
+    /// Rotated out token, accepted until it expires.
+    pub previous_auth: Option<(String, SystemTime)>,
+    pub metrics: Metrics,
+    pub listing: ListingCache,
+    pub tombstones: Tombstones,
+    pub cache: NoteCache,
+    pub locks: NoteLocks,
+    /// Set once the server stops accepting new work (see `POST /drain`).
+    pub draining: AtomicBool,
+    pub in_flight: AtomicUsize,
+    pub idle: Arc<IdleTracker>,
+}
+
+/// Count of a request that is being handled, released when dropped so aborted connections don't leak it.
+struct InFlight(Arc<State>);
+
+impl InFlight {
+    fn new(state: Arc<State>) -> Self {
+        state.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(state)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl State {
+    /// Set up the state of the server instance in `path`, continuing from its last saved metrics.
+    fn new(path: &Path, config: ServerConfig, auth: String) -> io::Result<Self> {
+        let listing = ListingCache::new(&config);
+        let tombstones = Tombstones::new(Duration::from_secs(config.tombstone_window));
+        let cache = NoteCache::new(config.note_cache_bytes);
+
+        // Continue counting from the last saved metrics.
+        let metrics = match &config.metrics_file {
+            Some(file) => Metrics::from_snapshot(&Snapshot::load(file)?),
+            None => Metrics::default(),
+        };
+
+        // Build the listing cache up front, so the first `/list` is fast.
+        if config.cache_listing {
+            listing.get(&path.join("./notes"))?;
+        }
+
+        let previous_auth = config.previous_token.clone().zip(config.previous_token_expires)
+            .map(|(token, expires)| (token, UNIX_EPOCH + Duration::from_secs(expires)));
+
+        Ok(State {
+            config,
+            notes: path.join("./notes"),
+            meta: path.join("./meta"),
+            auth,
+            previous_auth,
+            metrics,
+            listing,
+            tombstones,
+            cache,
+            locks: NoteLocks::default(),
+            draining: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            idle: Arc::new(IdleTracker::new()),
+        })
+    }
+}
 
 pub async fn start(args: &ArgMatches) -> io::Result<()> {
     // Read the command line arguments:
-    let path = args.get_one::<PathBuf>("path").expect("Missing path.");
-    let addr = format!("127.0.0.1:{}", args.get_one::<String>("port").expect("Missing addr."))
-        .to_socket_addrs()?.next()
-        .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+    let (path, port) = run_target(args)?;
+    let mut config = ServerConfig::load(&path.join("config.toml"))?;
+    config.resolve_paths(&path);
+    logs::init(config.log_file.as_deref())?;
+    if let Some(size) = args.get_one::<u64>("max-body-size") {
+        config.max_body_size = *size;
+    }
+    if let Some(timeout) = args.get_one::<u64>("timeout") {
+        config.read_timeout = *timeout;
+    }
+    if let Some(origins) = args.get_many::<String>("cors-origin") {
+        config.cors_origins = origins.cloned().collect();
+    }
+    let auth = auth_token(std::env::var("PYNE_TOKEN").ok(), &config)?;
+
+    let state = Arc::new(State::new(&path, config, auth)?);
+
+    // Periodically save the metrics, so they survive restarts.
+    if state.config.metrics_file.is_some() {
+        tokio::spawn(flush_metrics(state.clone()));
+    }
+
+    // Close connections that stopped sending or receiving data.
+    if state.config.idle_timeout > 0 {
+        tokio::spawn(reap_idle(state.clone()));
+    }
 
-    // Load the tls files, and get notes directory.
+    // Serve over a Unix domain socket (without TLS) if requested.
+    if let Some(socket) = args.get_one::<PathBuf>("unix") {
+        return serve_unix(socket, state).await;
+    }
+
+    let host = args.get_one::<String>("host").expect("Missing host.");
+    let addr = listen_addr(host, port.unwrap_or(state.config.port))?;
+
+    // Load the tls files.
     let certs = tls::load_certs(&path.join("./server.crt"))?;
     let key = tls::load_keys(&path.join("./server.key"))?;
-    let notes = path.join("./notes");
 
-    let config = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)
-        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
-    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let acceptor = TlsAcceptor::from(Arc::new(tls::server_config(certs, key, &state.config)?));
 
     // Start listening.
     let listener = TcpListener::bind(&addr).await?;
 
     println!("Server listening at https://{addr}");
 
+    // Redirect plaintext requests to the HTTPS listener.
+    if let Some(port) = state.config.http_redirect_port {
+        let redirect = TcpListener::bind((addr.ip(), port)).await?;
+
+        println!("Redirecting http://{}:{port} to https://{addr}", addr.ip());
+        tokio::spawn(serve_redirect(redirect, addr.port(), state.clone()));
+    }
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut connections = JoinSet::new();
+
     loop {
-        let (stream, _peer_addr) = listener.accept().await?;
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            // Clean up after finished connections.
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            () = &mut shutdown => break,
+        };
+
+        // Drop connections from clients that aren't allowed, before the TLS handshake.
+        if !state.config.allows_ip(peer_addr.ip()) {
+            continue;
+        }
         let acceptor = acceptor.clone();
-        let notes = notes.clone();
+        let state = state.clone();
+        let activity = state.idle.register();
+        let activity_id = activity.id();
+        let stream = TrackedStream::new(stream, activity);
 
         // Handle the incoming stream:
-        let fut = async move {
-            let stream = acceptor.accept(stream).await?;
-            
-            handle_conn(stream, &notes, "1234").await
+        let fut = {
+            let state = state.clone();
+            async move {
+                let timeout = Duration::from_secs(state.config.read_timeout);
+                let stream = tokio::time::timeout(timeout, acceptor.accept(stream)).await
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Timed out during the TLS handshake"))??;
+
+                handle_conn(stream, peer_addr.to_string(), state).await
+            }
         };
 
         // Print any errors that might've occured.
-        tokio::spawn(async move {
+        let idle = state.idle.clone();
+        let handle = connections.spawn(async move {
             if let Err(err) = fut.await {
-                eprintln!("{:?}", err);
+                log_conn_error(&err);
             }
         });
+        idle.attach(activity_id, handle);
     }
+
+    finish_connections(connections).await;
+
+    // Keep what was counted since the last flush.
+    save_metrics(&state);
+    Ok(())
 }
 
-/// Read a note and return it as a HTTP response.
-fn read_note(path: &PathBuf) -> HttpResponse {
-    let Ok(file) = fs::read_to_string(path) else {
-        return HttpResponse::not_found();
+/// Read the instance directory and the port given to `run`, which may leave out the port but still give a directory.
+fn run_target(args: &ArgMatches) -> io::Result<(PathBuf, Option<u16>)> {
+    let path = args.get_one::<PathBuf>("path").expect("Missing path.");
+    let Some(port) = args.get_one::<String>("port") else {
+        return Ok((path.clone(), None));
     };
-    let mut response = HttpResponse::ok();
 
-    response.text(&file);
-    response
+    match port.parse::<u16>() {
+        Ok(port) => Ok((path.clone(), Some(port))),
+        // A lone directory (`pyne run <PATH>`) takes the place of the port.
+        Err(_) if args.value_source("path") == Some(ValueSource::DefaultValue) => Ok((PathBuf::from(port), None)),
+        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid port `{port}`."))),
+    }
 }
 
-/// Write a note and return it as a HTTP response.
-fn write_note(path: &PathBuf, body: &str) -> HttpResponse {
-    if path.to_string_lossy().contains("..") {
-        return HttpResponse::err_with_context("'..' is not allowed in note paths.");
-    };
+/// Get the auth token, the one from the environment takes precedence over the config file.
+fn auth_token(env: Option<String>, config: &ServerConfig) -> io::Result<String> {
+    env.filter(|token| !token.trim().is_empty())
+        .or_else(|| config.token.clone())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No auth token configured, set `PYNE_TOKEN` or `token` in config.toml."))
+}
+
+/// Resolve the address to listen on, the host may be an IP address or a hostname.
+fn listen_addr(host: &str, port: u16) -> io::Result<SocketAddr> {
+    (host, port).to_socket_addrs()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid host `{host}`: {err}")))?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, format!("Host `{host}` has no addresses.")))
+}
+
+/// Wait for Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(err) => {
+                log::error!("Failed to listen for SIGTERM: {err}");
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
 
-    if let Err(_) = fs::write(path, body) {
-        HttpResponse::err_with_context("Failed to save note file.")
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Give the connections that are still being handled some time to finish.
+async fn finish_connections(mut connections: JoinSet<()>) {
+    println!("Shutting down, waiting for {} connection(s) to finish", connections.len());
+
+    let finished = tokio::time::timeout(Duration::from_secs(SHUTDOWN_TIMEOUT), async {
+        while connections.join_next().await.is_some() {}
+    }).await;
+
+    if finished.is_err() {
+        log::error!("Gave up on {} connection(s) after {SHUTDOWN_TIMEOUT}s", connections.len());
+    }
+}
+
+/// Log a connection error, clients going away mid-response are only logged for debugging.
+fn log_conn_error(err: &io::Error) {
+    let disconnected = matches!(
+        err.kind(),
+        io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::NotConnected
+    );
+
+    if disconnected {
+        log::debug!("Client disconnected: {err}");
     } else {
-        read_note(path)
+        log::error!("{err:?}");
     }
 }
 
-/// Delete a note.
-fn delete_note(path: &PathBuf) -> HttpResponse {
-    if let Err(err) = fs::remove_file(path) {
-        match err.kind() {
-            io::ErrorKind::NotFound => HttpResponse::not_found(),
-            _ => HttpResponse::err_with_context(&err.to_string()),
+/// Save the metrics to the metrics file on every flush interval.
+async fn flush_metrics(state: Arc<State>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.metrics_flush_interval.max(1)));
+
+    loop {
+        interval.tick().await;
+        save_metrics(&state);
+    }
+}
+
+/// Save the metrics to the metrics file, if there is one.
+fn save_metrics(state: &State) {
+    if let Some(file) = &state.config.metrics_file {
+        if let Err(err) = state.metrics.snapshot().save(file) {
+            log::error!("Failed to save metrics: {err}");
         }
-    } else {
-        HttpResponse::ok()
     }
 }
 
-/// Evaluate an incoming HTTP request.
-fn eval_request(request: &HttpRequest, notes_dir: &PathBuf) -> HttpResponse {
-    match request.path.as_str() {
-        /* Read or Write a note */
-        s if s.starts_with("/notes") => {
-            let dir = notes_dir.join(format!(".{}", &s[6..]));
+/// Close idle connections, checking a few times per idle timeout.
+async fn reap_idle(state: Arc<State>) {
+    let timeout = Duration::from_secs(state.config.idle_timeout);
+    let mut interval = tokio::time::interval((timeout / 4).max(Duration::from_millis(250)));
 
-            match request.req_type {
-                RequestType::GET => read_note(&dir),
-                RequestType::POST => write_note(&dir, &request.body),
-                RequestType::DELETE => delete_note(&dir),
+    loop {
+        interval.tick().await;
 
-                _ => HttpResponse::not_found()
-            }
+        let reaped = state.idle.reap(timeout);
+        if reaped > 0 {
+            log::debug!("Closed {reaped} idle connection(s)");
+            state.metrics.record_reaped(reaped);
         }
-        
-        /* List all notes */
-        s if s.starts_with("/list") => {
-            if s.len() <= 6 || !s.contains('?') {
-                return HttpResponse::err_with_context("Missing query string '?<start>:<end>'");
+    }
+}
+
+/// Answer every plaintext request with a redirect to the HTTPS port.
+async fn serve_redirect(listener: TcpListener, https_port: u16, state: Arc<State>) -> io::Result<()> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+
+        // Clients that may not connect over HTTPS aren't pointed to it either.
+        if !state.config.allows_ip(peer_addr.ip()) {
+            continue;
+        }
+        let limits = RequestLimits::new(&state.config);
+        let timeout = Duration::from_secs(state.config.read_timeout);
+
+        let fut = async move {
+            let (mut reader, mut writer) = split(stream);
+
+            // Give up on clients that never finish sending their request.
+            let req = tokio::time::timeout(timeout, HttpRequest::parse(&mut reader, limits)).await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out reading the request")))?;
+
+            // Keep the host the client used, but swap out the port.
+            let host = req.header("Host").unwrap_or("localhost");
+            let host = host.rsplit_once(':').filter(|(_, port)| port.parse::<u16>().is_ok()).map_or(host, |(host, _)| host);
+
+            // Keep the query string, which isn't part of the parsed path.
+            let target = req.head.split_whitespace().nth(1).unwrap_or("/");
+
+            HttpResponse::moved_permanently(&format!("https://{host}:{https_port}{target}"))
+                .send(&mut writer).await?;
+            writer.shutdown().await
+        };
+
+        // Print any errors that might've occured.
+        tokio::spawn(async move {
+            if let Err(err) = fut.await {
+                log_conn_error(&err);
             }
+        });
+    }
+}
 
-            // Bounds checks:
-            let mut bounds = s[6..].split(':');
-            let Some(start) = bounds.next() else {
-                return HttpResponse::err_with_context("Missing start bounds '?<start>:<end>'");
-            };
-            let Some(end) = bounds.next() else {
-                return HttpResponse::err_with_context("Missing end bounds '?<start>:<end>'");
-            };
+/// Accept plaintext connections on a Unix domain socket.
+#[cfg(unix)]
+async fn serve_unix(socket: &Path, state: Arc<State>) -> io::Result<()> {
+    // Remove a stale socket left behind by a previous run.
+    if socket.exists() {
+        fs::remove_file(socket)?;
+    }
+    let listener = tokio::net::UnixListener::bind(socket)?;
 
-            let Ok(start) = start.parse::<u16>() else {
-                return HttpResponse::err_with_context("Start bounds is not a valid number");
-            };
-            let Ok(end) = end.parse::<u16>() else {
-                return HttpResponse::err_with_context("End bounds is not a valid number");
-            };
+    println!("Server listening at unix:{}", socket.display());
 
-            if start > end {
-                return HttpResponse::err_with_context("Start of the bounds is bigger then the end");
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut connections = JoinSet::new();
+
+    loop {
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            // Clean up after finished connections.
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            () = &mut shutdown => break,
+        };
+        let state = state.clone();
+        let activity = state.idle.register();
+        let activity_id = activity.id();
+        let stream = TrackedStream::new(stream, activity);
+
+        // Print any errors that might've occured.
+        let idle = state.idle.clone();
+        let handle = connections.spawn(async move {
+            if let Err(err) = handle_conn(stream, "unix".to_owned(), state.clone()).await {
+                log_conn_error(&err);
             }
+        });
+        idle.attach(activity_id, handle);
+    }
 
-            let mut notes: Vec<String> = Vec::new();
-            let mut paths = fs::read_dir("notes").unwrap();
-            let mut i = 0;
+    finish_connections(connections).await;
 
-            while let Some(Ok(entry)) = paths.next() {
-                if i < start || i >= end {
-                    i += 1;
-                    continue;
+    // Keep what was counted since the last flush.
+    save_metrics(&state);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_socket: &Path, _state: Arc<State>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform."))
+}
+
+/// Slugify the file name of a note path, keeping its directory and extension, `None` if nothing of the name is left.
+fn slugify_path(path: &str) -> Option<String> {
+    let (dir, file) = path.rsplit_once('/').unwrap_or(("", path));
+    let (stem, ext) = match file.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, Some(ext)),
+        _ => (file, None),
+    };
+
+    let mut slug = String::with_capacity(stem.len());
+    for c in stem.chars().flat_map(char::to_lowercase) {
+        match c {
+            'a'..='z' | '0'..='9' | '_' => slug.push(c),
+            ' ' | '-' if !slug.is_empty() && !slug.ends_with('-') => slug.push('-'),
+            _ => {}
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() && !file.is_empty() {
+        return None;
+    }
+
+    match ext {
+        Some(ext) => Some(format!("{dir}/{slug}.{}", ext.to_lowercase())),
+        None => Some(format!("{dir}/{slug}")),
+    }
+}
+
+/// Resolve a requested note name to a path inside the notes directory.
+fn resolve_note(notes_dir: &Path, name: &str, config: &ServerConfig) -> Result<PathBuf, HttpResponse> {
+    // Control characters (e.g. NUL, newlines) break filesystems and listings.
+    if name.chars().any(char::is_control) {
+        return Err(HttpResponse::bad_request("Control characters are not allowed in note names."));
+    }
+
+    // Note paths are strictly relative to the notes directory.
+    let name = name.trim_start_matches('/');
+    let drive = name.len() >= 2 && name.as_bytes()[0].is_ascii_alphabetic() && name.as_bytes()[1] == b':';
+    let absolute = Path::new(name).components()
+        .any(|part| matches!(part, Component::RootDir | Component::Prefix(_)));
+
+    if drive || absolute || name.starts_with('\\') {
+        return Err(HttpResponse::bad_request("Absolute note paths are not allowed."));
+    }
+    if Path::new(name).components().any(|part| matches!(part, Component::ParentDir)) {
+        return Err(HttpResponse::bad_request("'..' is not allowed in note paths."));
+    }
+
+    let mut path = notes_dir.join(name);
+
+    if config.case_insensitive_paths && !path.exists() {
+        path = find_ignore_case(&path).unwrap_or(path);
+    }
+
+    // Symlinks could still lead outside of the notes directory.
+    if !is_inside(notes_dir, &path) {
+        return Err(HttpResponse::forbidden("Note paths must stay inside the notes directory."));
+    }
+    Ok(path)
+}
+
+/// Check if a path resolves to somewhere inside a directory, following symlinks.
+fn is_inside(dir: &Path, path: &Path) -> bool {
+    let Ok(root) = dir.canonicalize() else {
+        return false;
+    };
+
+    // Paths that don't exist yet are checked by their closest existing ancestor.
+    for ancestor in path.ancestors() {
+        match ancestor.canonicalize() {
+            Ok(resolved) => return resolved.starts_with(&root),
+            // A dangling symlink could point anywhere.
+            Err(_) if ancestor.symlink_metadata().is_ok() => return false,
+            Err(_) => continue,
+        }
+    }
+    false
+}
+
+/// Find an existing file whose name matches the path's file name ignoring case.
+fn find_ignore_case(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+
+    fs::read_dir(path.parent()?).ok()?
+        .filter_map(Result::ok)
+        .find(|entry| entry.file_name().to_string_lossy().to_lowercase() == name)
+        .map(|entry| entry.path())
+}
+
+/// A single rename of a `/rename-many` batch.
+#[derive(Deserialize)]
+struct Rename {
+    from: String,
+    to: String,
+}
+
+/// Rename a batch of notes, nothing is renamed unless every pair is valid.
+fn rename_many(body: &[u8], state: &State) -> HttpResponse {
+    let renames: Vec<Rename> = match serde_json::from_slice(body) {
+        Ok(renames) => renames,
+        Err(err) => return HttpResponse::bad_request(&format!("Expected a JSON list of {{ from, to }} pairs: {err}")),
+    };
+
+    let mut paths = Vec::with_capacity(renames.len());
+    for rename in &renames {
+        let from = match resolve_note(&state.notes, &rename.from, &state.config) {
+            Ok(from) => from,
+            Err(response) => return response,
+        };
+        let to = match resolve_note(&state.notes, &rename.to, &state.config) {
+            Ok(to) => to,
+            Err(response) => return response,
+        };
+        paths.push((from, to));
+    }
+
+    // Hold the locks of every note involved, so no other write interleaves with the batch.
+    let _locks = state.locks.lock_all(paths.iter().flat_map(|(from, to)| [from.as_path(), to.as_path()]));
+
+    // Validate all the pairs before touching any notes.
+    for (i, (rename, (from, to))) in renames.iter().zip(&paths).enumerate() {
+        if !from.is_file() {
+            return HttpResponse::bad_request(&format!("Note '{}' doesn't exist.", rename.from));
+        }
+        if to.exists() || paths[..i].iter().any(|(_, other)| other == to) {
+            return HttpResponse::conflict(&format!("Note '{}' already exists.", rename.to));
+        }
+
+        let sidecar = meta::sidecar_path(&state.meta, &state.notes, from);
+        if sidecar.as_deref().map(NoteMeta::load).transpose().ok().flatten().is_some_and(|meta| meta.readonly) {
+            return HttpResponse::forbidden(&format!("Note '{}' is read-only.", rename.from));
+        }
+    }
+
+    let mut results = Vec::with_capacity(renames.len());
+    for (rename, (from, to)) in renames.iter().zip(&paths) {
+        let renamed = to.parent().map_or(Ok(()), fs::create_dir_all).and_then(|()| fs::rename(from, to));
+
+        // Move the metadata along with the note.
+        if renamed.is_ok() {
+            let sidecars = (meta::sidecar_path(&state.meta, &state.notes, from), meta::sidecar_path(&state.meta, &state.notes, to));
+            if let (Some(old), Some(new)) = sidecars {
+                if old.exists() {
+                    let _ = new.parent().map_or(Ok(()), fs::create_dir_all).and_then(|()| fs::rename(old, new));
                 }
+            }
+        }
+        state.cache.invalidate(from);
+        state.cache.invalidate(to);
+        state.tombstones.remove(to);
+
+        results.push(match renamed {
+            Ok(()) => serde_json::json!({ "from": rename.from, "to": rename.to, "renamed": true }),
+            Err(err) => serde_json::json!({ "from": rename.from, "to": rename.to, "renamed": false, "error": err.to_string() }),
+        });
+    }
+    state.listing.invalidate();
+
+    let mut response = HttpResponse::ok();
+    response.json(&serde_json::Value::from(results).to_string());
+    response
+}
+
+/// Tags to add to and remove from a `/tag` batch of notes.
+#[derive(Deserialize)]
+struct Retag {
+    notes: Vec<String>,
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+/// Add and remove tags in the front-matter of a batch of notes.
+fn tag_notes(body: &[u8], state: &State) -> HttpResponse {
+    let retag: Retag = match serde_json::from_slice(body) {
+        Ok(retag) => retag,
+        Err(err) => return HttpResponse::bad_request(&format!("Expected {{ notes, add, remove }}: {err}")),
+    };
+
+    let paths: Vec<Result<PathBuf, HttpResponse>> = retag.notes.iter()
+        .map(|name| resolve_note(&state.notes, name, &state.config))
+        .collect();
 
-                notes.push(entry.file_name().to_string_lossy().to_string());
-                i += 1;
+    // Hold the locks of every note in the batch, so no other write interleaves with it.
+    let _locks = state.locks.lock_all(paths.iter().flatten().map(PathBuf::as_path));
+
+    let results: Vec<serde_json::Value> = retag.notes.iter().zip(&paths).map(|(name, path)| {
+        let path = match path {
+            Ok(path) => path,
+            Err(response) => {
+                return serde_json::json!({ "note": name, "tagged": false, "error": response.context() });
             }
+        };
 
-            let mut response = HttpResponse::ok();
-            response.json(&format!("[\"{}\"]", notes.join("\", \"")));
-            response
+        let sidecar = meta::sidecar_path(&state.meta, &state.notes, path);
+        if sidecar.as_deref().map(NoteMeta::load).transpose().ok().flatten().is_some_and(|meta| meta.readonly) {
+            return serde_json::json!({ "note": name, "tagged": false, "error": "Note is read-only." });
         }
 
-        /* Get server status */
-        "/status" => HttpResponse::ok(),
+        let retagged = fs::read_to_string(path).and_then(|content| {
+            let (note, tags) = frontmatter::retag(&content, &retag.add, &retag.remove);
+            write_atomic(path, note.as_bytes()).map(|()| tags)
+        });
+        state.cache.invalidate(path);
 
-        _ => HttpResponse::not_found()
-    }
+        match retagged {
+            Ok(tags) => serde_json::json!({ "note": name, "tagged": true, "tags": tags }),
+            Err(err) => serde_json::json!({ "note": name, "tagged": false, "error": err.to_string() }),
+        }
+    }).collect();
+    state.listing.invalidate();
+
+    let mut response = HttpResponse::ok();
+    response.json(&serde_json::Value::from(results).to_string());
+    response
 }
 
-/// Handle an incoming connection.
-async fn handle_conn(stream: TlsStream<TcpStream>, notes_dir: &PathBuf, auth: &str) -> io::Result<()> {
-    let (mut reader, mut writer) = split(stream);
+/// Read a note and return it as a HTTP response.
+fn read_note(path: &Path, state: &State) -> HttpResponse {
+    // Don't mask unreadable notes as missing, but don't reveal why either.
+    let unreadable = |err: io::Error| match err.kind() {
+        io::ErrorKind::PermissionDenied => HttpResponse::forbidden("Note is not readable."),
+        _ => HttpResponse::not_found(),
+    };
 
-    let req = HttpRequest::parse(&mut reader).await?;
+    // Check the note before reading it, so content read while it's being written is cached as the older version.
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(err) => return unreadable(err),
+    };
+    let modified = meta.modified().unwrap_or(UNIX_EPOCH);
 
-    // Check if the auth header is valid:
-    let Some(auth_header) = req.headers.get("Authorization") else {
-        return HttpResponse::unauth().send(&mut writer).await;
+    let file = match state.cache.get(path, modified, meta.len()) {
+        Some(file) => file,
+        None => {
+            let file = match fs::read(path) {
+                Ok(file) => file,
+                Err(err) => return unreadable(err),
+            };
+            state.cache.insert(path, modified, &file);
+            Arc::new(file)
+        }
+    };
+    let mut response = HttpResponse::ok();
+
+    // Notes without a known extension are text unless they hold other bytes.
+    let content_type = match mime_type(path) {
+        "text/plain" if std::str::from_utf8(&file).is_err() => "application/octet-stream",
+        content_type => content_type,
     };
-    if auth_header.trim() != auth.trim() {
-        return HttpResponse::unauth().send(&mut writer).await;
+
+    response.bytes(&file);
+    response.header("ETag", &note_etag(&file));
+
+    // Expose the file metadata, for clients that only want to read headers.
+    response.header("X-Note-Size", &file.len().to_string());
+    if let Ok(modified) = meta.modified() {
+        response.header("X-Note-Modified", &http_date(modified));
+    }
+    if let Ok(created) = meta.created() {
+        response.header("X-Note-Created", &http_date(created));
+    }
+    if state.config.utf8_charset {
+        response.content_type(&with_charset(content_type));
+    } else {
+        response.content_type(content_type);
+    }
+    response
+}
+
+/// Get the entity tag of the content of a note.
+fn note_etag(content: &[u8]) -> String {
+    format!("\"{}\"", hex(digest(&SHA256, content).as_ref()))
+}
+
+/// Encode bytes as lowercase hexadecimal.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Trim trailing whitespace from each line, and end the body with a single newline.
+fn normalize_body(body: &str) -> String {
+    let lines: Vec<&str> = body.lines().map(str::trim_end).collect();
+    let mut normalized = lines.join("\n").trim_end().to_owned();
+
+    if !normalized.is_empty() {
+        normalized.push('\n');
     }
+    normalized
+}
 
-    let res = eval_request(&req, &notes_dir);
+/// Replace the content of a file all at once, by writing it to a temporary file next to it first.
+/// Readers (and crashes) never see a partially written file this way.
+fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    // Write through symlinks, instead of replacing them.
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
 
-    res.send(&mut writer).await?;
-    writer.shutdown().await
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let suffix = Alphanumeric.sample_string(&mut rand::thread_rng(), 8);
+    let temp = path.with_file_name(format!(".{name}.tmp-{suffix}"));
+
+    let result = fs::File::create(&temp).and_then(|mut file| {
+        file.write_all(content)?;
+
+        // Keep the permissions of the file being replaced.
+        if let Ok(meta) = fs::metadata(&path) {
+            file.set_permissions(meta.permissions())?;
+        }
+        file.sync_all()
+    });
+
+    match result.and_then(|()| fs::rename(&temp, &path)) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            let _ = fs::remove_file(&temp);
+            Err(err)
+        }
+    }
+}
+
+/// Write a note and return it as a HTTP response.
+fn write_note(path: &Path, body: &[u8], state: &State) -> HttpResponse {
+    let body = prepare_body(body, state);
+
+    // Don't serve the old content from the cache.
+    state.cache.invalidate(path);
+
+    let existed = path.exists();
+    if write_atomic(path, &body).is_err() {
+        return HttpResponse::err_with_context("Failed to save note file.");
+    }
+    if existed {
+        return read_note(path, state);
+    }
+    created_note(path, state)
+}
+
+/// Parse a `Content-Range` header (e.g. `bytes 0-99/200`) into the first, last and total bytes.
+fn parse_content_range(range: &str) -> Option<(u64, u64, u64)> {
+    let (range, total) = range.strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+
+    Some((first.trim().parse().ok()?, last.trim().parse().ok()?, total.trim().parse().ok()?))
+}
+
+/// Write part of a note at the offset given by a `Content-Range` header, so large notes can be uploaded in pieces.
+fn write_note_range(path: &Path, range: &str, body: &[u8], state: &State) -> HttpResponse {
+    let len = fs::metadata(path).map_or(0, |meta| meta.len());
+
+    // The range has to match the body, and may not leave a gap after the current content.
+    let valid = parse_content_range(range).filter(|&(first, last, total)| {
+        first <= last && last < total && last - first + 1 == body.len() as u64 && first <= len
+    });
+    let Some((first, last, total)) = valid else {
+        return HttpResponse::range_not_satisfiable(len);
+    };
+
+    // Don't serve the old content from the cache.
+    state.cache.invalidate(path);
+
+    let existed = path.exists();
+    let result = fs::OpenOptions::new().write(true).create(true).truncate(false).open(path).and_then(|mut file| {
+        file.seek(SeekFrom::Start(first))?;
+        file.write_all(body)?;
+
+        // The last piece drops whatever came after the new content.
+        if last + 1 == total {
+            file.set_len(total)?;
+        }
+        Ok(())
+    });
+
+    match result {
+        Ok(()) if existed => HttpResponse::no_content(),
+        Ok(()) => created_note(path, state),
+        Err(err) => HttpResponse::err_with_context(&err.to_string()),
+    }
+}
+
+/// Write a new note without overwriting an existing one, suffixing its name (e.g. `note-2.md`) until it's unique.
+fn write_unique_note(path: &Path, body: &[u8], state: &State) -> HttpResponse {
+    let body = prepare_body(body, state);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+
+    for n in 1..=MAX_UNIQUE_SUFFIX {
+        let candidate = match n {
+            1 => path.to_owned(),
+            _ => path.with_file_name(format!("{stem}-{n}{extension}")),
+        };
+
+        // Creating exclusively keeps concurrent writers from claiming the same name.
+        let file = fs::OpenOptions::new().write(true).create_new(true).open(&candidate);
+        match file {
+            Ok(mut file) => {
+                if let Err(err) = io::Write::write_all(&mut file, &body) {
+                    return HttpResponse::err_with_context(&err.to_string());
+                }
+                state.tombstones.remove(&candidate);
+                return created_note(&candidate, state);
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return HttpResponse::err_with_context(&err.to_string()),
+        }
+    }
+    HttpResponse::conflict("No unique name is left for the note.")
+}
+
+/// Normalize the body of a note being written, if enabled.
+fn prepare_body(body: &[u8], state: &State) -> Vec<u8> {
+    // Only text notes can have their whitespace normalized.
+    match std::str::from_utf8(body) {
+        Ok(text) if state.config.normalize_whitespace => normalize_body(text).into_bytes(),
+        _ => body.to_vec(),
+    }
+}
+
+/// Answer the creation of a note, pointing the client at it.
+fn created_note(path: &Path, state: &State) -> HttpResponse {
+    let mut response = HttpResponse::created();
+    response.header("Location", &note_location(path, state));
+    response
+}
+
+/// Get the URL path a note is served at, which may be in a collection.
+fn note_location(path: &Path, state: &State) -> String {
+    for (name, dir) in &state.config.collections {
+        if let Ok(relative) = path.strip_prefix(dir) {
+            return format!("/c/{name}/notes/{}", percent_encode(&relative.to_string_lossy()));
+        }
+    }
+
+    let name = path.strip_prefix(&state.notes).unwrap_or(path).to_string_lossy();
+    format!("/notes/{}", percent_encode(&name))
+}
+
+/// Create an empty note, which must not exist yet.
+fn create_empty_note(path: &Path, state: &State) -> HttpResponse {
+    if let Some(dir) = path.parent() {
+        if let Err(err) = fs::create_dir_all(dir) {
+            return HttpResponse::err_with_context(&err.to_string());
+        }
+    }
+
+    match fs::OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(_) => created_note(path, state),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => HttpResponse::conflict("Note already exists."),
+        Err(err) => HttpResponse::err_with_context(&err.to_string()),
+    }
+}
+
+/// Truncate a note to at most the given length in bytes.
+fn truncate_note(path: &Path, len: u64) -> HttpResponse {
+    let file = match fs::OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return HttpResponse::not_found(),
+        Err(err) => return HttpResponse::err_with_context(&err.to_string()),
+    };
+
+    // Only ever shrink the note, never pad it.
+    let result = file.metadata().and_then(|meta| file.set_len(len.min(meta.len())));
+
+    match result {
+        Ok(()) => HttpResponse::ok(),
+        Err(err) => HttpResponse::err_with_context(&err.to_string()),
+    }
+}
+
+/// Delete a note, optionally returning its content.
+fn delete_note(path: &Path, return_content: bool) -> HttpResponse {
+    // Grab the content before it's gone.
+    let content = if return_content {
+        match fs::read(path) {
+            Ok(content) => Some(content),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return HttpResponse::not_found(),
+            Err(err) => return HttpResponse::err_with_context(&err.to_string()),
+        }
+    } else {
+        None
+    };
+
+    if let Err(err) = fs::remove_file(path) {
+        match err.kind() {
+            io::ErrorKind::NotFound => HttpResponse::not_found(),
+            _ => HttpResponse::err_with_context(&err.to_string()),
+        }
+    } else {
+        let mut response = HttpResponse::ok();
+        if let Some(content) = content {
+            response.bytes(&content);
+        }
+        response
+    }
+}
+
+/// Get the request headers browsers may send cross-origin, which includes the auth header.
+fn cors_headers(auth_header: &str) -> String {
+    let mut headers = vec!["Authorization", "Content-Type", "If-Match", "If-None-Match"];
+    if !headers.iter().any(|header| header.eq_ignore_ascii_case(auth_header)) {
+        headers.push(auth_header);
+    }
+    headers.join(", ")
+}
+
+/// Evaluate an incoming HTTP request.
+fn eval_request(request: &HttpRequest, state: &State) -> HttpResponse {
+    // Notes of a collection are addressed as `/c/<collection>/notes/...`.
+    let (route, notes_dir, meta_dir) = match request.path.strip_prefix("/c/") {
+        Some(rest) => {
+            let (name, route) = rest.split_once('/').unwrap_or((rest, ""));
+            let route = format!("/{route}");
+
+            match state.config.collections.get(name) {
+                Some(dir) if route.starts_with("/notes") => (route, dir.clone(), state.meta.join(".collections").join(name)),
+                _ => return HttpResponse::not_found(),
+            }
+        }
+        None => (request.path.clone(), state.notes.clone(), state.meta.clone()),
+    };
+    let notes_dir = &notes_dir;
+    let collection = &request.path[..request.path.len() - route.len()];
+
+    // Echo the request back for debugging, with the credentials redacted.
+    if matches!(request.req_type, RequestType::TRACE) {
+        if !state.config.debug_trace {
+            return HttpResponse::not_implemented();
+        }
+
+        let mut response = HttpResponse::ok();
+        response.content_type("message/http");
+        response.text(&request.redacted_head(&state.config.auth_header));
+        return response;
+    }
+
+    // Tell browsers which cross-origin requests they may send.
+    if request.is_preflight() {
+        let methods = if route.starts_with("/notes") { NOTE_METHODS } else { SERVER_METHODS };
+
+        let mut response = HttpResponse::no_content();
+        response.header("Access-Control-Allow-Methods", &methods.join(", "));
+        response.header("Access-Control-Allow-Headers", &cors_headers(&state.config.auth_header));
+        response.header("Access-Control-Max-Age", &CORS_MAX_AGE.to_string());
+        return response;
+    }
+
+    match route.as_str() {
+        /* Read or Write a note */
+        s if s.starts_with("/notes") => {
+            let mut name = percent_decode(&s[6..]);
+
+            // Tidy up the name of notes being written.
+            let requested = name.clone();
+            if matches!(request.req_type, RequestType::POST) {
+                if state.config.slugify_names {
+                    name = match slugify_path(&name) {
+                        Some(slug) => slug,
+                        None => return HttpResponse::bad_request("Note name has no characters left after slugifying."),
+                    };
+                }
+                if state.config.append_md_extension && Path::new(&name).extension().is_none() && !name.ends_with('/') {
+                    name.push_str(".md");
+                }
+            }
+
+            // Dotfiles (or notes inside dot directories) are only served if enabled.
+            let hidden = Path::new(&name).components()
+                .any(|part| matches!(part, Component::Normal(part) if part.to_string_lossy().starts_with('.')));
+            if hidden && !state.config.serve_hidden && matches!(request.req_type, RequestType::GET | RequestType::HEAD) {
+                return HttpResponse::not_found();
+            }
+
+            let dir = match resolve_note(notes_dir, &name, &state.config) {
+                Ok(dir) => dir,
+                Err(response) => return response,
+            };
+
+            // Writes to the same note take turns, so they never interleave.
+            let writes = matches!(request.req_type, RequestType::POST | RequestType::DELETE);
+            let _write_lock = match writes {
+                true if state.config.reject_concurrent_writes => match state.locks.try_lock(&dir) {
+                    Some(lock) => Some(lock),
+                    None => return HttpResponse::conflict("Note is being written by another request."),
+                },
+                true => Some(state.locks.lock(&dir)),
+                false => None,
+            };
+
+            // The notes directory itself has no sidecar.
+            let sidecar = meta::sidecar_path(&meta_dir, notes_dir, &dir);
+            let mut meta = match sidecar.as_deref().map(NoteMeta::load).transpose() {
+                Ok(meta) => meta.unwrap_or_default(),
+                Err(err) => return HttpResponse::err_with_context(&err.to_string()),
+            };
+
+            // Only POST can (un)protect a note, and only it may do so while the note is read-only.
+            let protect = request.param("protect");
+            let protecting = matches!(request.req_type, RequestType::POST) && protect.is_some();
+            if protect.is_some() && !protecting {
+                return HttpResponse::bad_request("Only POST can protect or unprotect a note.");
+            }
+            if !matches!(protect, None | Some("true") | Some("false")) {
+                return HttpResponse::bad_request("Protect must be 'true' or 'false'.");
+            }
+
+            // Read-only notes can't be changed until they're unprotected.
+            if meta.readonly && writes && !protecting {
+                return HttpResponse::forbidden("Note is read-only.");
+            }
+
+            match request.req_type {
+                /* Protect or unprotect a note */
+                RequestType::POST if protecting => {
+                    let (true, Some(sidecar)) = (dir.is_file(), &sidecar) else {
+                        return HttpResponse::not_found();
+                    };
+
+                    meta.readonly = protect == Some("true");
+                    match meta.save(sidecar) {
+                        Ok(()) => HttpResponse::ok(),
+                        Err(err) => HttpResponse::err_with_context(&err.to_string()),
+                    }
+                }
+
+                /* Truncate a note */
+                RequestType::POST if request.param("truncate").is_some() => {
+                    let Ok(len) = request.param("truncate").unwrap_or_default().parse::<u64>() else {
+                        return HttpResponse::bad_request("Truncate length is not a valid number");
+                    };
+                    let response = truncate_note(&dir, len);
+                    state.listing.invalidate();
+                    state.cache.invalidate(&dir);
+                    response
+                }
+
+                /* Export a note with front-matter */
+                RequestType::GET | RequestType::HEAD if request.param("export").is_some() => {
+                    let Some(format) = request.param("export").and_then(ExportFormat::parse) else {
+                        return HttpResponse::bad_request("Unknown export format.");
+                    };
+                    let (Ok(content), Ok(modified)) = (fs::read_to_string(&dir), dir.metadata().and_then(|meta| meta.modified())) else {
+                        return HttpResponse::not_found();
+                    };
+                    let title = dir.file_stem().unwrap_or_default().to_string_lossy();
+
+                    let mut response = HttpResponse::ok();
+                    response.text(&format.render(&title, modified, &content));
+                    response.chunked();
+                    if state.config.utf8_charset {
+                        response.content_type(&with_charset("text/markdown"));
+                    } else {
+                        response.content_type("text/markdown");
+                    }
+                    if state.config.note_digests {
+                        response.digest();
+                    }
+                    response
+                }
+
+                // HEAD gets the same response, the body is left out when sending.
+                RequestType::GET | RequestType::HEAD => {
+                    let mut response = read_note(&dir, state);
+
+                    // Let clients know the note existed, but was deleted.
+                    if response.status_code() == 404 && state.tombstones.contains(&dir) {
+                        return HttpResponse::gone();
+                    }
+
+                    // Only return the note if it's the version the client asked for.
+                    if let (200, Some(tags)) = (response.status_code(), request.header("If-Match")) {
+                        let etag = note_etag(response.content().unwrap_or_default());
+                        if !tags.split(',').any(|tag| tag.trim() == "*" || tag.trim() == etag) {
+                            return HttpResponse::precondition_failed();
+                        }
+                    }
+                    if state.config.note_digests && response.status_code() == 200 {
+                        response.digest();
+                    }
+                    response
+                }
+                RequestType::POST => {
+                    let mut response = if request.param("empty") == Some("true") {
+                        create_empty_note(&dir, state)
+                    } else if state.config.require_body && request.body.is_empty() {
+                        return HttpResponse::bad_request("Missing note body, use '?empty=true' to create an empty note.");
+                    } else if let Some(range) = request.header("Content-Range") {
+                        write_note_range(&dir, range, &request.body, state)
+                    } else if request.param("unique") == Some("true") {
+                        write_unique_note(&dir, &request.body, state)
+                    } else {
+                        write_note(&dir, &request.body, state)
+                    };
+                    state.listing.invalidate();
+                    state.cache.invalidate(&dir);
+                    state.tombstones.remove(&dir);
+
+                    // Tell the client under which name the note was stored.
+                    if name != requested {
+                        response.header("Content-Location", &format!("{collection}/notes{}", percent_encode(&name)));
+                    }
+                    response
+                }
+                RequestType::DELETE => {
+                    let response = delete_note(&dir, request.param("return") == Some("true"));
+                    state.listing.invalidate();
+                    state.cache.invalidate(&dir);
+
+                    if response.status_code() == 200 {
+                        state.tombstones.bury(&dir);
+                        if let Some(sidecar) = &sidecar {
+                            let _ = fs::remove_file(sidecar);
+                        }
+                    }
+                    response
+                }
+
+                /* Read-only WebDAV discovery of the notes */
+                RequestType::PROPFIND if name.trim_matches('/').is_empty() => {
+                    let entries = match state.listing.get(notes_dir) {
+                        Ok(entries) => entries,
+                        Err(err) => return HttpResponse::err_with_context(&err.to_string()),
+                    };
+
+                    let mut response = HttpResponse::multi_status();
+                    response.xml(&listing::to_multistatus(&entries, collection));
+                    response
+                }
+
+                /* Report the methods allowed on this note */
+                RequestType::OPTIONS => {
+                    let mut response = HttpResponse::no_content();
+                    if name.trim_matches('/').is_empty() {
+                        response.header("Allow", &NOTES_DIR_METHODS.join(", "));
+                    } else {
+                        response.header("Allow", &NOTE_METHODS.join(", "));
+                    }
+                    response
+                }
+
+                _ if name.trim_matches('/').is_empty() => HttpResponse::method_not_allowed(NOTES_DIR_METHODS),
+                _ => HttpResponse::method_not_allowed(NOTE_METHODS)
+            }
+        }
+        
+        /* Report the capabilities of the server */
+        "*" if matches!(request.req_type, RequestType::OPTIONS) => {
+            let mut response = HttpResponse::no_content();
+            response.header("Allow", &SERVER_METHODS.join(", "));
+            response
+        }
+
+        /* List all notes */
+        s if s.starts_with("/list") => {
+            // Bounds checks, falling back to the old `?<start>:<end>` form:
+            let legacy = request.query.keys().find_map(|key| key.split_once(':'));
+            let Some(start) = request.param("start").or(legacy.map(|(start, _)| start)) else {
+                return HttpResponse::err_with_context("Missing query parameter 'start'");
+            };
+            let Some(end) = request.param("end").or(legacy.map(|(_, end)| end)) else {
+                return HttpResponse::err_with_context("Missing query parameter 'end'");
+            };
+
+            let Ok(start) = start.parse::<u16>() else {
+                return HttpResponse::err_with_context("Start bounds is not a valid number");
+            };
+            let Ok(end) = end.parse::<u16>() else {
+                return HttpResponse::err_with_context("End bounds is not a valid number");
+            };
+
+            if start > end {
+                return HttpResponse::err_with_context("Start of the bounds is bigger then the end");
+            }
+
+            let Some(sort) = request.param("sort").filter(|sort| !sort.is_empty()).map_or(Some(ListSort::default()), ListSort::parse) else {
+                return HttpResponse::bad_request("Unknown sort order, use 'name', '-name', 'mtime' or '-mtime'.");
+            };
+
+            let mut entries = match state.listing.get(notes_dir) {
+                Ok(entries) => entries,
+                Err(err) => return HttpResponse::err_with_context(&err.to_string()),
+            };
+            // Directory order isn't stable, which would shuffle the pages.
+            sort.sort(&mut entries);
+
+            let mut page: Vec<&ListEntry> = Vec::new();
+            let mut hasher = Context::new(&SHA256);
+
+            for (i, entry) in entries.iter().enumerate() {
+                // The ETag and state token cover the names and modified times of the listed notes.
+                let modified = entry.modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+                hasher.update(&(entry.name.len() as u64).to_le_bytes());
+                hasher.update(entry.name.as_bytes());
+                hasher.update(&modified.map_or(u128::MAX, |modified| modified.as_nanos()).to_le_bytes());
+
+                if i < start as usize || i >= end as usize {
+                    continue;
+                }
+
+                page.push(entry);
+            }
+
+            // Let the client use its cached listing if nothing changed.
+            let html = request.negotiate(&["application/json", "text/html"]) == Some("text/html");
+            let state_token = hex(hasher.finish().as_ref());
+            let etag = format!("\"{state_token}-{start}-{end}{}\"", if html { "-html" } else { "" });
+            let unchanged = request.param("if_changed") == Some(state_token.as_str());
+            if unchanged || request.header("If-None-Match").is_some_and(|tag| tag == etag) {
+                let mut response = HttpResponse::not_modified();
+                response.header("ETag", &etag);
+                response.header("X-State-Token", &state_token);
+                return response;
+            }
+
+            // Browsers get a table they can page through.
+            let mut response = HttpResponse::ok();
+            if html {
+                response.html(&listing::to_html(&page, start, end, entries.len(), sort));
+            } else {
+                let notes: Vec<serde_json::Value> = page.iter().map(|entry| entry.to_json()).collect();
+
+                // The end is of the notes returned, so clients know when they've run out.
+                let listing = serde_json::json!({
+                    "total": entries.len(),
+                    "start": start,
+                    "end": start as usize + notes.len(),
+                    "notes": notes,
+                });
+                response.json(&listing.to_string());
+            }
+            response.header("ETag", &etag);
+            response.header("X-State-Token", &state_token);
+            response
+        }
+
+        /* Search the contents of the notes */
+        "/search" => {
+            let Some(term) = request.param("q").filter(|term| !term.is_empty()) else {
+                return HttpResponse::bad_request("Missing query parameter 'q'");
+            };
+            let limit = match request.param("limit").map(str::parse::<usize>) {
+                Some(Ok(limit)) => limit,
+                Some(Err(_)) => return HttpResponse::bad_request("Limit is not a valid number"),
+                None => state.config.search_limit,
+            };
+            let Ok(offset) = request.param("offset").unwrap_or("0").parse::<usize>() else {
+                return HttpResponse::bad_request("Offset is not a valid number");
+            };
+
+            let Ok(snippet_len) = request.param("snippet_length").map_or(Ok(state.config.search_snippet_length), str::parse::<usize>) else {
+                return HttpResponse::bad_request("Snippet length is not a valid number");
+            };
+
+            match search::search(notes_dir, term, state.config.serve_hidden, snippet_len) {
+                Ok(matches) => {
+                    let page: Vec<serde_json::Value> = matches.iter().skip(offset).take(limit).map(|found| found.to_json()).collect();
+
+                    // The total stops counting at `MAX_SEARCH_MATCHES`.
+                    let mut response = HttpResponse::ok();
+                    response.json(&serde_json::json!({
+                        "total_matches": matches.len(),
+                        "offset": offset,
+                        "matches": page,
+                    }).to_string());
+                    response
+                }
+                Err(err) => HttpResponse::err_with_context(&err.to_string()),
+            }
+        }
+
+        /* List the most recently modified notes */
+        s if s.starts_with("/recent") => {
+            let Ok(limit) = request.param("limit").unwrap_or("10").parse::<usize>() else {
+                return HttpResponse::bad_request("Limit is not a valid number");
+            };
+
+            let mut entries = match state.listing.get(notes_dir) {
+                Ok(entries) => entries,
+                Err(err) => return HttpResponse::err_with_context(&err.to_string()),
+            };
+
+            // Only the newest `limit` entries need to be sorted.
+            let newest_first = |a: &ListEntry, b: &ListEntry| b.modified.cmp(&a.modified);
+            if limit < entries.len() {
+                entries.select_nth_unstable_by(limit, newest_first);
+                entries.truncate(limit);
+            }
+            entries.sort_by(newest_first);
+
+            let notes: Vec<serde_json::Value> = entries.iter()
+                .map(|entry| serde_json::json!({
+                    "name": entry.name,
+                    "modified": entry.modified.and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|time| time.as_secs()),
+                }))
+                .collect();
+
+            let mut response = HttpResponse::ok();
+            response.json(&serde_json::Value::Array(notes).to_string());
+            response
+        }
+
+        /* Get server status */
+        "/status" => {
+            let status = serde_json::json!({ "version": env!("CARGO_PKG_VERSION"), "git_commit": env!("GIT_HASH") });
+
+            let mut response = HttpResponse::ok();
+            response.json(&status.to_string());
+            response
+        }
+
+        /* Get server readiness, which goes down once drained */
+        "/health" => {
+            if state.draining.load(Ordering::SeqCst) && state.in_flight.load(Ordering::SeqCst) == 0 {
+                HttpResponse::unavailable(DRAIN_RETRY_AFTER)
+            } else {
+                HttpResponse::ok()
+            }
+        }
+
+        /* Stop accepting new requests */
+        "/drain" => match request.req_type {
+            RequestType::POST => {
+                state.draining.store(true, Ordering::SeqCst);
+                HttpResponse::ok()
+            }
+
+            _ => HttpResponse::method_not_allowed(&["POST"])
+        }
+
+        /* Rename a batch of notes */
+        "/rename-many" => match request.req_type {
+            RequestType::POST => rename_many(&request.body, state),
+
+            _ => HttpResponse::method_not_allowed(&["POST"])
+        }
+
+        /* Tag a batch of notes */
+        "/tag" => match request.req_type {
+            RequestType::POST => tag_notes(&request.body, state),
+
+            _ => HttpResponse::method_not_allowed(&["POST"])
+        }
+
+        /* Get the last lines of the log file */
+        "/logs" => {
+            let Some(file) = &state.config.log_file else {
+                return HttpResponse::not_found();
+            };
+            let Ok(lines) = request.param("lines").unwrap_or("100").parse::<usize>() else {
+                return HttpResponse::bad_request("Lines is not a valid number");
+            };
+
+            match logs::tail(file, lines) {
+                Ok(tail) => {
+                    let mut response = HttpResponse::ok();
+                    response.text(&tail);
+                    response
+                }
+                Err(err) => HttpResponse::err_with_context(&err.to_string()),
+            }
+        }
+
+        /* Get the identity of the authenticated client */
+        "/whoami" => {
+            // There's a single token with full access to all notes.
+            let identity = serde_json::json!({ "label": "default", "scope": "all", "namespace": null });
+
+            let mut response = HttpResponse::ok();
+            response.json(&identity.to_string());
+            response
+        }
+
+        /* Get the effective configuration */
+        "/config" => match serde_json::to_string(&state.config) {
+            Ok(config) => {
+                let mut response = HttpResponse::ok();
+                response.json(&config);
+                response
+            }
+            Err(err) => HttpResponse::err_with_context(&err.to_string()),
+        }
+
+        /* Get a summary of the TLS settings */
+        "/tls-info" => match tls::summary(&state.config.tls_min_version) {
+            Ok(summary) => {
+                let mut response = HttpResponse::ok();
+                response.json(&summary.to_string());
+                response
+            }
+            Err(err) => HttpResponse::err_with_context(&err.to_string()),
+        }
+
+        /* Get the request metrics */
+        "/metrics" => {
+            let mut response = HttpResponse::ok();
+            response.json(&state.metrics.snapshot().to_json());
+            response
+        }
+
+        /* Get the request metrics and zero them */
+        "/metrics/reset" => match request.req_type {
+            RequestType::POST => {
+                let mut response = HttpResponse::ok();
+                response.json(&state.metrics.reset().to_json());
+                response
+            }
+
+            _ => HttpResponse::method_not_allowed(&["POST"])
+        }
+
+        _ => HttpResponse::not_found()
+    }
+}
+
+/// Compare two byte strings in time independent of their contents, so tokens can't be guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Log a request that has been answered, to form an access log.
+fn log_request(peer: &str, method: &str, path: &str, res: &HttpResponse, head_only: bool) {
+    log::info!("{peer} \"{method} {path}\" {} {}", res.status_code(), sent_size(res, head_only));
+}
+
+/// Warn about responses that took longer or were bigger than the configured thresholds.
+fn warn_if_heavy(path: &str, res: &HttpResponse, head_only: bool, elapsed: Duration, config: &ServerConfig) {
+    let size = sent_size(res, head_only) as u64;
+    let slow = config.slow_response_ms > 0 && elapsed.as_millis() > config.slow_response_ms as u128;
+    let large = config.large_response_bytes > 0 && size > config.large_response_bytes;
+
+    if slow || large {
+        log::warn!("{path}: heavy response, took {}ms to send {size} bytes", elapsed.as_millis());
+    }
+}
+
+/// Get the size of the body that is sent for a response.
+fn sent_size(res: &HttpResponse, head_only: bool) -> usize {
+    if head_only { 0 } else { res.content().map_or(0, <[u8]>::len) }
+}
+
+/// Handle an incoming connection.
+async fn handle_conn<S: AsyncRead + AsyncWrite>(stream: S, peer: String, state: Arc<State>) -> io::Result<()> {
+    let (mut reader, mut writer) = split(stream);
+
+    // Give up on clients that never finish sending their request.
+    let timeout = Duration::from_secs(state.config.read_timeout);
+    let parsed = tokio::time::timeout(timeout, HttpRequest::parse(&mut reader, RequestLimits::new(&state.config))).await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out reading the request")));
+
+    // Reject malformed requests with a 400:
+    let req = match parsed {
+        Ok(req) => req,
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+            let mut res = if err.get_ref().is_some_and(|inner| inner.is::<UriTooLong>()) {
+                HttpResponse::uri_too_long()
+            } else if err.get_ref().is_some_and(|inner| inner.is::<LengthRequired>()) {
+                HttpResponse::length_required()
+            } else if err.get_ref().is_some_and(|inner| inner.is::<PayloadTooLarge>()) {
+                HttpResponse::payload_too_large()
+            } else {
+                HttpResponse::bad_request(&err.to_string())
+            };
+            res.allow_origin(None, &state.config.cors_origins);
+            state.metrics.record(res.status_code());
+
+            res.send(&mut writer).await?;
+            log_request(&peer, "-", "-", &res, false);
+            return writer.shutdown().await;
+        }
+        Err(err) => return Err(err),
+    };
+    let started = Instant::now();
+
+    if state.config.log_headers {
+        log::debug!("{}", req.redacted_head(&state.config.auth_header).trim_end());
+    }
+
+    let path = req.path.clone();
+    let method = format!("{:?}", req.req_type);
+    let head_only = matches!(req.req_type, RequestType::HEAD);
+    let origin = req.header("Origin").map(str::to_owned);
+    let accepts_html = req.negotiate(&["text/plain", "text/html"]) == Some("text/html");
+
+    // Check if the auth header is valid:
+    let authorized = req.header(&state.config.auth_header).is_some_and(|header| {
+        let previous = state.previous_auth.as_ref()
+            .filter(|(_, expires)| SystemTime::now() < *expires)
+            .is_some_and(|(token, _)| constant_time_eq(header.as_bytes(), token.trim().as_bytes()));
+
+        constant_time_eq(header.as_bytes(), state.auth.trim().as_bytes()) || previous
+    });
+
+    // Following the logs never finishes, so it's streamed from here instead of being evaluated.
+    let draining = state.draining.load(Ordering::SeqCst);
+    let follow = matches!(req.req_type, RequestType::GET) && req.path == "/logs" && req.param("follow") == Some("true");
+    if let (true, false, true, Some(file)) = (authorized, draining, follow, &state.config.log_file) {
+        let _in_flight = InFlight::new(state.clone());
+        state.metrics.record(200);
+        log_request(&peer, &method, &path, &HttpResponse::ok(), true);
+        return logs::follow(file, &state.draining, &mut reader, &mut writer).await;
+    }
+
+    // Browsers don't send credentials with preflight requests.
+    let mut res = if !authorized && !req.is_preflight() {
+        HttpResponse::unauth()
+    } else if req.path == "/health" || req.is_preflight() {
+        eval_request(&req, &state)
+    } else if draining {
+        HttpResponse::unavailable(DRAIN_RETRY_AFTER)
+    } else {
+        // Evaluate off the async runtime, so slow handlers can be cut off.
+        let deadline = Duration::from_secs(state.config.request_deadline);
+        let handler = {
+            let state = state.clone();
+            // Handlers cut off by the deadline keep running, so they're counted until they return.
+            let in_flight = InFlight::new(state.clone());
+            tokio::task::spawn_blocking(move || {
+                let _in_flight = in_flight;
+                eval_request(&req, &state)
+            })
+        };
+
+        let res = match tokio::time::timeout(deadline, handler).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(err)) => HttpResponse::err_with_context(&err.to_string()),
+            Err(_) => HttpResponse::unavailable(state.config.request_deadline),
+        };
+        res
+    };
+    // Don't leak error details (e.g. filesystem paths) to clients in production.
+    if state.config.production && res.status_code() == 500 {
+        log::error!("{path}: {}", res.context());
+        res = HttpResponse::err();
+    }
+
+    // Serve custom error pages to browsers.
+    if let (true, Some(dir)) = (accepts_html, &state.config.error_pages) {
+        let code = res.status_code();
+
+        if code == 404 || code == 500 {
+            if let Ok(page) = fs::read_to_string(dir.join(format!("{code}.html"))) {
+                res.html(&page);
+            }
+        }
+    }
+    res.allow_origin(origin.as_deref(), &state.config.cors_origins);
+    state.metrics.record(res.status_code());
+
+    if head_only {
+        res.send_head(&mut writer).await?;
+    } else if state.config.buffer_responses {
+        res.send(&mut writer).await?;
+    } else {
+        res.send_streamed(&mut writer).await?;
+    }
+    log_request(&peer, &method, &path, &res, head_only);
+    warn_if_heavy(&path, &res, head_only, started.elapsed(), &state.config);
+    writer.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{Instance, TempDir};
+
+    fn resolve_status(notes: &Path, name: &str) -> u16 {
+        match resolve_note(notes, name, &ServerConfig::default()) {
+            Ok(_) => 200,
+            Err(response) => response.status_code(),
+        }
+    }
+
+    #[test]
+    fn resolve_note_rejects_control_characters() {
+        let notes = TempDir::new("control");
+        assert_eq!(resolve_status(notes.path(), "a\0b.md"), 400);
+        assert_eq!(resolve_status(notes.path(), "a\nb.md"), 400);
+        assert_eq!(resolve_status(notes.path(), "a\rb.md"), 400);
+    }
+
+    #[test]
+    fn resolve_note_rejects_absolute_paths() {
+        let notes = TempDir::new("absolute");
+        assert_eq!(resolve_status(notes.path(), "C:/Windows/win.ini"), 400);
+        assert_eq!(resolve_status(notes.path(), "c:note.md"), 400);
+        assert_eq!(resolve_status(notes.path(), "\\\\server\\share"), 400);
+    }
+
+    #[test]
+    fn resolve_note_rejects_parent_dirs() {
+        let notes = TempDir::new("parent");
+        assert_eq!(resolve_status(notes.path(), "../secret"), 400);
+        assert_eq!(resolve_status(notes.path(), "a/../../secret"), 400);
+        assert_eq!(resolve_status(notes.path(), "/../secret"), 400);
+    }
+
+    #[test]
+    fn resolve_note_stays_in_notes_dir() {
+        let notes = TempDir::new("inside");
+        assert_eq!(resolve_note(notes.path(), "/a/b.md", &ServerConfig::default()).ok(), Some(notes.join("a/b.md")));
+        assert_eq!(resolve_note(notes.path(), "c.d.md", &ServerConfig::default()).ok(), Some(notes.join("c.d.md")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_note_rejects_symlinks_outside() {
+        let notes = TempDir::new("symlink");
+        std::os::unix::fs::symlink(std::env::temp_dir(), notes.join("out")).unwrap();
+        assert_eq!(resolve_status(notes.path(), "out/note.md"), 403);
+    }
+
+    fn run_args(args: &[&str]) -> ArgMatches {
+        let matches = crate::cli().try_get_matches_from([&["pyne", "run"], args].concat()).unwrap();
+        matches.subcommand_matches("run").unwrap().clone()
+    }
+
+    #[test]
+    fn run_without_port_listens_on_config_port() {
+        // Find a free port to stand in for the configured one.
+        let free = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = ServerConfig { port: free, ..ServerConfig::default() };
+
+        let (path, port) = run_target(&run_args(&["/srv/pyne"])).unwrap();
+        assert_eq!((path, port), (PathBuf::from("/srv/pyne"), None));
+
+        let addr = listen_addr("127.0.0.1", port.unwrap_or(config.port)).unwrap();
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), free);
+
+        assert_eq!(run_target(&run_args(&[])).unwrap(), (PathBuf::from("."), None));
+        assert_eq!(ServerConfig::default().port, 8443);
+    }
+
+    #[test]
+    fn run_takes_positional_port_and_path() {
+        assert_eq!(run_target(&run_args(&["9000", "/srv/pyne"])).unwrap(), (PathBuf::from("/srv/pyne"), Some(9000)));
+        assert_eq!(run_target(&run_args(&["9000"])).unwrap(), (PathBuf::from("."), Some(9000)));
+        assert!(run_target(&run_args(&["nope", "/srv/pyne"])).is_err());
+    }
+
+    #[test]
+    fn note_etag_is_the_sha256_of_the_content() {
+        assert_eq!(note_etag(b""), "\"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\"");
+        assert_ne!(note_etag(b"a"), note_etag(b"b"));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_contents() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"Token"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(!constant_time_eq(b"", b"token"));
+    }
+
+    #[test]
+    fn slugify_path_keeps_directory_and_extension() {
+        assert_eq!(slugify_path("/Hello World.MD").as_deref(), Some("/hello-world.md"));
+        assert_eq!(slugify_path("/Sub Dir/My  Note!.md").as_deref(), Some("/Sub Dir/my-note.md"));
+        assert_eq!(slugify_path("/- Trimmed -").as_deref(), Some("/trimmed"));
+        assert_eq!(slugify_path("/snake_case.txt").as_deref(), Some("/snake_case.txt"));
+        assert_eq!(slugify_path("/sub/").as_deref(), Some("/sub/"));
+    }
+
+    #[test]
+    fn slugify_path_rejects_empty_slugs() {
+        assert_eq!(slugify_path("/!!!.md"), None);
+        assert_eq!(slugify_path("/dir/???"), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn slow_handlers_stay_in_flight_after_the_deadline() {
+        let instance = Instance::with_config("slow-handler", ServerConfig { request_deadline: 1, ..ServerConfig::default() });
+        // Reading a named pipe blocks until something is written to it.
+        testing::fifo(&instance.note("slow.md"));
+
+        let res = instance.get("/notes/slow.md").await;
+        let in_flight = instance.state.in_flight.load(Ordering::SeqCst);
+
+        // Let the handler finish.
+        let fifo = instance.note("slow.md");
+        tokio::task::spawn_blocking(move || fs::write(fifo, "done")).await.unwrap().unwrap();
+
+        assert_eq!(res.status, 503);
+        assert_eq!(res.header("Retry-After"), Some("1"));
+        assert_eq!(in_flight, 1);
+        assert!(testing::eventually(|| instance.state.in_flight.load(Ordering::SeqCst) == 0).await);
+    }
+
+    #[tokio::test]
+    async fn following_logs_streams_new_lines_until_draining() {
+        use tokio::io::AsyncReadExt;
+
+        let instance = Instance::with_config("follow", ServerConfig { log_file: Some("pyne.log".into()), ..ServerConfig::default() });
+        let log = instance.dir.join("pyne.log");
+        fs::write(&log, "old line\n").unwrap();
+
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let conn = tokio::spawn(handle_conn(server, "test".to_owned(), instance.state.clone()));
+        client.write_all(format!("GET /logs?follow=true HTTP/1.1\r\nAuthorization: {}\r\n\r\n", testing::TOKEN).as_bytes()).await.unwrap();
+
+        // Only lines logged after the stream started are sent.
+        let mut received = Vec::new();
+        testing::read_until(&mut client, &mut received, "\r\n\r\n").await;
+        assert_eq!(instance.state.in_flight.load(Ordering::SeqCst), 1);
+
+        fs::OpenOptions::new().append(true).open(&log).unwrap().write_all(b"first\nsecond\n").unwrap();
+        testing::read_until(&mut client, &mut received, "data: second\n\n").await;
+
+        // Draining ends the stream, so it doesn't hold up the shutdown.
+        instance.state.draining.store(true, Ordering::SeqCst);
+        client.read_to_end(&mut received).await.unwrap();
+        conn.await.unwrap().unwrap();
+
+        let received = String::from_utf8(received).unwrap();
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(received.contains("data: first\n\n"));
+        assert!(!received.contains("old line"));
+        assert!(received.ends_with("0\r\n\r\n"));
+        assert_eq!(instance.state.in_flight.load(Ordering::SeqCst), 0);
+
+        assert_eq!(instance.get("/logs?follow=true").await.status, 503);
+    }
+
+    #[tokio::test]
+    async fn search_pages_through_matches() {
+        let instance = Instance::with_config("search-paging", ServerConfig { search_limit: 2, ..ServerConfig::default() });
+        fs::write(instance.note("a.md"), "match 1\nmatch 2\nother\n").unwrap();
+        fs::write(instance.note("b.md"), "match 3\nmatch 4\nmatch 5\n").unwrap();
+
+        let lines = |res: &testing::Response| -> Vec<String> {
+            res.json()["matches"].as_array().unwrap().iter().map(|found| found["snippet"].as_str().unwrap().to_owned()).collect()
+        };
+
+        // The configured limit applies without a `limit`, and the total counts every match.
+        let res = instance.get("/search?q=match").await;
+        assert_eq!(res.json()["total_matches"], 5);
+        assert_eq!(lines(&res), ["match 1", "match 2"]);
+
+        let res = instance.get("/search?q=match&limit=2&offset=2").await;
+        assert_eq!(res.json()["offset"], 2);
+        assert_eq!(lines(&res), ["match 3", "match 4"]);
+        assert_eq!(lines(&instance.get("/search?q=match&limit=10&offset=4").await), ["match 5"]);
+        assert!(lines(&instance.get("/search?q=match&offset=9").await).is_empty());
+        assert_eq!(instance.get("/search?q=match&offset=x").await.status, 400);
+    }
+
+    #[tokio::test]
+    async fn search_snippet_length_can_be_overridden() {
+        let instance = Instance::with_config("search-snippets", ServerConfig { search_snippet_length: 30, ..ServerConfig::default() });
+        fs::write(instance.note("long.md"), format!("{} match {}", "ü".repeat(100), "ö".repeat(100))).unwrap();
+
+        let snippet = |res: testing::Response| res.json()["matches"][0]["snippet"].as_str().unwrap().chars().count();
+        assert_eq!(snippet(instance.get("/search?q=match").await), 30);
+        assert_eq!(snippet(instance.get("/search?q=match&snippet_length=10").await), 10);
+        assert_eq!(instance.get("/search?q=match&snippet_length=-1").await.status, 400);
+    }
+
+    /// Connect to a listener, send a raw request and read the whole response.
+    async fn exchange(addr: SocketAddr, request: &str) -> io::Result<String> {
+        use tokio::io::AsyncReadExt;
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn redirect_listener_points_to_https() {
+        let instance = Instance::with_config("redirect", ServerConfig { read_timeout: 1, ..ServerConfig::default() });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_redirect(listener, 8443, instance.state.clone()));
+
+        let response = exchange(addr, "GET /notes/a.md?raw=true HTTP/1.1\r\nHost: example.test:8080\r\n\r\n").await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 301 "));
+        assert!(response.contains("\r\nLocation: https://example.test:8443/notes/a.md?raw=true\r\n"));
+
+        // Clients that stall are cut off by the read timeout.
+        let started = Instant::now();
+        assert_eq!(exchange(addr, "GET / HTTP/1.1\r\n").await.unwrap(), "");
+        assert!(started.elapsed() < Duration::from_secs(5));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn redirect_listener_drops_blocked_clients() {
+        let config = ServerConfig { ip_allowlist: vec!["10.0.0.0/8".parse().unwrap()], ..ServerConfig::default() };
+        let instance = Instance::with_config("redirect-blocked", config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_redirect(listener, 8443, instance.state.clone()));
+
+        // The connection is closed without an answer, possibly resetting it.
+        let response = exchange(addr, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.map_or(true, |response| response.is_empty()));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn trace_redacts_the_auth_header_and_cookies() {
+        let config = ServerConfig { debug_trace: true, auth_header: "X-Pyne-Token".to_owned(), ..ServerConfig::default() };
+        let instance = Instance::with_config("trace-redacted", config);
+
+        let res = instance.request_with("TRACE", "/notes", &["Cookie: session=secret-cookie"], b"").await;
+        assert_eq!(res.status, 200);
+        assert!(res.text().contains("X-Pyne-Token: ***\r\n"));
+        assert!(res.text().contains("Cookie: ***\r\n"));
+        assert!(!res.text().contains(testing::TOKEN) && !res.text().contains("secret-cookie"));
+    }
+
+    #[tokio::test]
+    async fn trace_echoes_the_request_only_when_enabled() {
+        let instance = Instance::with_config("trace", ServerConfig { debug_trace: true, ..ServerConfig::default() });
+
+        let res = instance.request_with("TRACE", "/notes/a.md?x=1", &["X-Debug: yes"], b"").await;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.header("Content-Type"), Some("message/http"));
+        assert!(res.text().starts_with("TRACE /notes/a.md?x=1 HTTP/1.1\r\nHost: localhost\r\n"));
+        assert!(res.text().contains("X-Debug: yes\r\n"));
+
+        let disabled = Instance::with_config("trace-off", ServerConfig::default());
+        assert_eq!(disabled.request("TRACE", "/notes/a.md", b"").await.status, 501);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_writes_to_a_note_end_with_one_body() {
+        let instance = Arc::new(Instance::with_config("concurrent-writes", ServerConfig::default()));
+        let body = |i: usize| i.to_string().repeat(50_000);
+
+        let mut writes = JoinSet::new();
+        for i in 0..8 {
+            let (same, other) = (instance.clone(), instance.clone());
+            writes.spawn(async move { same.post("/notes/same.md", &body(i)).await.status });
+            writes.spawn(async move { other.post(&format!("/notes/other-{i}.md"), &body(i)).await.status });
+        }
+        while let Some(status) = writes.join_next().await {
+            assert!(matches!(status.unwrap(), 200 | 201));
+        }
+
+        let content = fs::read_to_string(instance.note("same.md")).unwrap();
+        assert!((0..8).any(|i| content == body(i)));
+        for i in 0..8 {
+            assert_eq!(fs::read_to_string(instance.note(&format!("other-{i}.md"))).unwrap(), body(i));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn readers_never_see_partial_writes() {
+        let instance = Arc::new(Instance::with_config("atomic-writes", ServerConfig::default()));
+        let body = |c: char| c.to_string().repeat(200_000);
+        fs::write(instance.note("note.md"), body('a')).unwrap();
+
+        let writer = {
+            let instance = instance.clone();
+            tokio::spawn(async move {
+                for c in "bcdefghijk".chars().cycle().take(40) {
+                    assert_eq!(instance.post("/notes/note.md", &body(c)).await.status, 200);
+                }
+            })
+        };
+
+        // Every read is one whole body, never a mix or a truncated one.
+        while !writer.is_finished() {
+            let res = instance.get("/notes/note.md").await;
+            let first = res.text().chars().next().unwrap();
+            assert_eq!(res.text(), body(first));
+        }
+        writer.await.unwrap();
+
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(instance.note("")).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn protected_notes_refuse_changes_until_unprotected() {
+        let instance = Instance::with_config("protect", ServerConfig::default());
+        fs::write(instance.note("a.md"), "original").unwrap();
+
+        assert_eq!(instance.post("/notes/a.md?protect=true", "").await.status, 200);
+        assert_eq!(instance.post("/notes/a.md", "changed").await.status, 403);
+        assert_eq!(instance.post("/notes/a.md?truncate=0", "").await.status, 403);
+        assert_eq!(instance.request("DELETE", "/notes/a.md", b"").await.status, 403);
+        assert_eq!(instance.get("/notes/a.md").await.text(), "original");
+
+        let res = instance.post("/rename-many", r#"[{ "from": "a.md", "to": "b.md" }]"#).await;
+        assert_eq!(res.status, 403);
+        assert!(res.text().contains("read-only"));
+        assert!(instance.note("a.md").exists() && !instance.note("b.md").exists());
+
+        assert_eq!(instance.post("/notes/a.md?protect=false", "").await.status, 200);
+        assert_eq!(instance.post("/notes/a.md", "changed").await.status, 200);
+        assert_eq!(instance.get("/notes/a.md").await.text(), "changed");
+    }
+
+    #[tokio::test]
+    async fn protect_only_takes_true_or_false_on_post() {
+        let instance = Instance::with_config("protect-params", ServerConfig::default());
+        fs::write(instance.note("a.md"), "original").unwrap();
+
+        assert_eq!(instance.request("DELETE", "/notes/a.md?protect=true", b"").await.status, 400);
+        assert_eq!(instance.get("/notes/a.md?protect=false").await.status, 400);
+        assert_eq!(instance.post("/notes/a.md?protect=yes", "").await.status, 400);
+        assert_eq!(instance.post("/notes/missing.md?protect=true", "").await.status, 404);
+        assert!(instance.note("a.md").exists());
+    }
+
+    #[tokio::test]
+    async fn metrics_continue_from_the_saved_snapshot() {
+        let config = || ServerConfig { metrics_file: Some("metrics.json".into()), ..ServerConfig::default() };
+        let instance = Instance::with_config("metrics-restart", config());
+        instance.get("/notes/missing.md").await;
+        instance.get("/health").await;
+        save_metrics(&instance.state);
+
+        // A restarted server picks up the counts where they were left.
+        let mut config = config();
+        config.resolve_paths(instance.dir.path());
+        let restarted = State::new(instance.dir.path(), config, testing::TOKEN.to_owned()).unwrap();
+        let snapshot = restarted.metrics.snapshot();
+        assert_eq!((snapshot.requests, snapshot.status), (2, [0, 1, 0, 1, 0]));
+
+        restarted.metrics.record(200);
+        assert_eq!(restarted.metrics.snapshot().requests, 3);
+    }
+
+    #[tokio::test]
+    async fn delete_returns_the_content_when_asked() {
+        let instance = Instance::with_config("delete-return", ServerConfig::default());
+        fs::write(instance.note("a.md"), "last look").unwrap();
+        fs::write(instance.note("b.md"), "gone").unwrap();
+
+        let res = instance.request("DELETE", "/notes/a.md?return=true", b"").await;
+        assert_eq!((res.status, res.text()), (200, "last look"));
+        assert!(!instance.note("a.md").exists());
+
+        let res = instance.request("DELETE", "/notes/b.md", b"").await;
+        assert_eq!(res.status, 200);
+        assert!(!res.text().contains("gone"));
+        assert!(!instance.note("b.md").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_serves_requests() {
+        use tokio::io::AsyncReadExt;
+
+        let instance = Instance::with_config("unix-socket", ServerConfig::default());
+        fs::write(instance.note("a.md"), "over the socket").unwrap();
+        let socket = instance.dir.join("pyne.sock");
+        let server = {
+            let (socket, state) = (socket.clone(), instance.state.clone());
+            tokio::spawn(async move { serve_unix(&socket, state).await })
+        };
+        assert!(testing::eventually(|| socket.exists()).await);
+
+        let mut stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+        stream.write_all(format!("GET /notes/a.md HTTP/1.1\r\nAuthorization: {}\r\n\r\n", testing::TOKEN).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        server.abort();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nover the socket"));
+    }
+
+    #[tokio::test]
+    async fn metrics_reset_returns_the_counts_and_zeroes_them() {
+        let instance = Instance::with_config("metrics-reset", ServerConfig::default());
+        instance.get("/health").await;
+        instance.get("/notes/missing.md").await;
+        instance.get("/notes/missing.md").await;
+
+        let res = instance.post("/metrics/reset", "").await;
+        assert_eq!(res.json()["requests"], 3);
+        assert_eq!(res.json()["status"]["2xx"], 1);
+        assert_eq!(res.json()["status"]["4xx"], 2);
+
+        // Only the reset itself was counted since.
+        let snapshot = instance.state.metrics.snapshot();
+        assert_eq!((snapshot.requests, snapshot.status), (1, [0, 1, 0, 0, 0]));
+        assert_eq!(instance.get("/metrics/reset").await.status, 405);
+    }
+
+    #[tokio::test]
+    async fn whitespace_is_only_normalized_when_enabled() {
+        let body = "line  \nnext\t\n\n\n";
+
+        let exact = Instance::with_config("whitespace-off", ServerConfig::default());
+        exact.post("/notes/a.md", body).await;
+        assert_eq!(fs::read_to_string(exact.note("a.md")).unwrap(), body);
+
+        let normalized = Instance::with_config("whitespace-on", ServerConfig { normalize_whitespace: true, ..ServerConfig::default() });
+        normalized.post("/notes/a.md", body).await;
+        assert_eq!(fs::read_to_string(normalized.note("a.md")).unwrap(), "line\nnext\n");
+    }
+
+    #[tokio::test]
+    async fn list_etag_matches_until_a_note_is_added() {
+        let instance = Instance::with_config("list-etag", ServerConfig::default());
+        fs::write(instance.note("a.md"), "a").unwrap();
+
+        let res = instance.get("/list?start=0&end=10").await;
+        assert_eq!(res.status, 200);
+        let etag = res.header("ETag").unwrap().to_owned();
+
+        let cached = instance.request_with("GET", "/list?start=0&end=10", &[&format!("If-None-Match: {etag}")], b"").await;
+        assert_eq!((cached.status, cached.header("ETag")), (304, Some(etag.as_str())));
+        assert!(cached.body.is_empty());
+
+        instance.post("/notes/b.md", "b").await;
+        let changed = instance.request_with("GET", "/list?start=0&end=10", &[&format!("If-None-Match: {etag}")], b"").await;
+        assert_eq!(changed.status, 200);
+        assert_ne!(changed.header("ETag"), Some(etag.as_str()));
+        assert_eq!(changed.json()["total"], 2);
+    }
+
+    #[tokio::test]
+    async fn case_insensitive_paths_find_the_existing_note() {
+        let sensitive = Instance::with_config("case-sensitive", ServerConfig::default());
+        fs::write(sensitive.note("Todo.md"), "stored").unwrap();
+        assert_eq!(sensitive.get("/notes/todo.md").await.status, 404);
+
+        let insensitive = Instance::with_config("case-insensitive", ServerConfig { case_insensitive_paths: true, ..ServerConfig::default() });
+        fs::write(insensitive.note("Todo.md"), "stored").unwrap();
+        assert_eq!(insensitive.get("/notes/TODO.md").await.text(), "stored");
+
+        // Updates keep the case the note was stored with.
+        assert_eq!(insensitive.post("/notes/todo.md", "updated").await.status, 200);
+        let names: Vec<_> = fs::read_dir(insensitive.note("")).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, ["Todo.md"]);
+        assert_eq!(fs::read_to_string(insensitive.note("Todo.md")).unwrap(), "updated");
+    }
+
+    #[tokio::test]
+    async fn cached_listing_reflects_written_notes() {
+        let instance = Instance::with_config("listing-cache", ServerConfig { cache_listing: true, ..ServerConfig::default() });
+        let names = |res: testing::Response| -> Vec<String> {
+            res.json()["notes"].as_array().unwrap().iter().map(|note| note["name"].as_str().unwrap().to_owned()).collect()
+        };
+        assert!(names(instance.get("/list?start=0&end=10").await).is_empty());
+
+        instance.post("/notes/a.md", "a").await;
+        assert_eq!(names(instance.get("/list?start=0&end=10").await), ["a.md"]);
+
+        instance.request("DELETE", "/notes/a.md", b"").await;
+        assert!(names(instance.get("/list?start=0&end=10").await).is_empty());
+    }
+
+    #[tokio::test]
+    async fn propfind_lists_the_notes_as_xml() {
+        let instance = Instance::with_config("propfind", ServerConfig::default());
+        fs::write(instance.note("a note.md"), "12345").unwrap();
+        fs::write(instance.note("b&c.md"), "").unwrap();
+
+        let res = instance.request("PROPFIND", "/notes/", b"").await;
+        assert_eq!(res.status, 207);
+        assert!(res.header("Content-Type").unwrap().contains("xml"));
+
+        let xml = res.text();
+        assert!(xml.contains("<D:href>/notes/a%20note.md</D:href>"));
+        assert!(xml.contains("<D:displayname>a note.md</D:displayname><D:getcontentlength>5</D:getcontentlength>"));
+        assert!(xml.contains("<D:displayname>b&amp;c.md</D:displayname><D:getcontentlength>0</D:getcontentlength>"));
+        assert_eq!(instance.request("PROPFIND", "/notes/a%20note.md", b"").await.status, 405);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn draining_refuses_new_requests_but_finishes_in_flight_ones() {
+        let instance = Arc::new(Instance::with_config("drain", ServerConfig::default()));
+        testing::fifo(&instance.note("slow.md"));
+
+        let slow = {
+            let instance = instance.clone();
+            tokio::spawn(async move { instance.get("/notes/slow.md").await })
+        };
+        let started = testing::eventually(|| instance.state.in_flight.load(Ordering::SeqCst) == 1).await;
+
+        let drained = instance.post("/drain", "").await.status;
+        let refused = instance.get("/list?start=0&end=10").await;
+        let health = instance.get("/health").await.status;
+
+        // Let the in-flight request finish.
+        let fifo = instance.note("slow.md");
+        tokio::task::spawn_blocking(move || fs::write(fifo, "finished")).await.unwrap().unwrap();
+        let slow = slow.await.unwrap();
+
+        assert!(started);
+        assert_eq!(drained, 200);
+        assert_eq!((refused.status, refused.header("Retry-After")), (503, Some("30")));
+        assert_eq!(health, 200);
+        assert_eq!((slow.status, slow.text()), (200, "finished"));
+        assert_eq!(instance.get("/health").await.status, 503);
+    }
+
+    #[tokio::test]
+    async fn buffered_and_streamed_responses_are_identical() {
+        let content: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        let request = format!("GET /notes/large.bin HTTP/1.1\r\nAuthorization: {}\r\n\r\n", testing::TOKEN);
+
+        let mut responses = Vec::new();
+        for buffer_responses in [true, false] {
+            let instance = Instance::with_config("buffering", ServerConfig { buffer_responses, ..ServerConfig::default() });
+            fs::write(instance.note("large.bin"), &content).unwrap();
+            let response = instance.exchange(request.as_bytes()).await;
+
+            // The dates may tick over between the two.
+            let end = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+            let head: Vec<&str> = std::str::from_utf8(&response[..end]).unwrap().split("\r\n")
+                .filter(|line| !["Date:", "X-Note-Modified:", "X-Note-Created:"].iter().any(|header| line.starts_with(header)))
+                .collect();
+            responses.push([head.join("\r\n").as_bytes(), &response[end..]].concat());
+        }
+
+        assert_eq!(responses[0], responses[1]);
+        assert!(responses[0].ends_with(&content));
+        assert!(String::from_utf8_lossy(&responses[0]).contains(&format!("\r\nContent-Length: {}\r\n", content.len())));
+    }
+
+    #[tokio::test]
+    async fn config_endpoint_redacts_the_tokens() {
+        let config = ServerConfig {
+            token: Some("config-secret".to_owned()),
+            previous_token: Some("previous-secret".to_owned()),
+            max_body_size: 1234,
+            ..ServerConfig::default()
+        };
+        let instance = Instance::with_config("config-endpoint", config);
+
+        let res = instance.get("/config").await;
+        assert_eq!(res.json()["max_body_size"], 1234);
+        assert_eq!(res.json()["auth_header"], "Authorization");
+        assert_eq!(res.json()["token"], "***");
+        assert_eq!(res.json()["previous_token"], "***");
+        assert!(!res.text().contains("secret") && !res.text().contains(testing::TOKEN));
+    }
+
+    #[tokio::test]
+    async fn production_hides_error_details_but_logs_them() {
+        testing::capture_logs();
+
+        let verbose = Instance::with_config("errors-verbose", ServerConfig::default());
+        let res = verbose.get("/list?end=10").await;
+        assert_eq!(res.status, 500);
+        assert!(res.text().contains("Missing query parameter 'start'"));
+
+        let production = Instance::with_config("errors-production", ServerConfig { production: true, ..ServerConfig::default() });
+        let res = production.get("/list?end=10").await;
+        assert_eq!(res.status, 500);
+        assert!(!res.text().contains("Missing query parameter"));
+
+        let logged = testing::logged("Missing query parameter 'start'");
+        assert_eq!(logged.len(), 1);
+        assert_eq!(logged[0].0, log::Level::Error);
+    }
+
+    /// Write a note that was last modified some seconds ago.
+    fn write_aged(path: &Path, seconds_ago: u64) {
+        fs::write(path, "").unwrap();
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(seconds_ago)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recent_lists_the_newest_notes_first() {
+        let instance = Instance::with_config("recent", ServerConfig::default());
+        for (name, age) in [("a.md", 300), ("b.md", 100), ("c.md", 200), ("d.md", 400)] {
+            write_aged(&instance.note(name), age);
+        }
+        let names = |res: testing::Response| -> Vec<String> {
+            res.json().as_array().unwrap().iter().map(|note| note["name"].as_str().unwrap().to_owned()).collect()
+        };
+
+        assert_eq!(names(instance.get("/recent?limit=3").await), ["b.md", "c.md", "a.md"]);
+        assert_eq!(names(instance.get("/recent").await), ["b.md", "c.md", "a.md", "d.md"]);
+        assert_eq!(names(instance.get("/recent?limit=0").await), Vec::<String>::new());
+
+        let newest = &instance.get("/recent?limit=1").await.json()[0];
+        let modified = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() - 100;
+        assert!(newest["modified"].as_u64().unwrap().abs_diff(modified) <= 2);
+    }
+
+    #[tokio::test]
+    async fn options_reports_the_allowed_methods() {
+        let instance = Instance::with_config("options", ServerConfig::default());
+
+        let note = instance.request("OPTIONS", "/notes/a.md", b"").await;
+        assert_eq!((note.status, note.header("Allow")), (204, Some("GET, HEAD, POST, DELETE, OPTIONS")));
+
+        let dir = instance.request("OPTIONS", "/notes/", b"").await;
+        assert_eq!((dir.status, dir.header("Allow")), (204, Some("PROPFIND, OPTIONS")));
+
+        let server = instance.request("OPTIONS", "*", b"").await;
+        assert_eq!((server.status, server.header("Allow")), (204, Some("GET, HEAD, POST, DELETE, PROPFIND, OPTIONS")));
+    }
+
+    #[tokio::test]
+    async fn html_clients_get_the_custom_error_pages() {
+        let instance = Instance::with_config("error-pages", ServerConfig { error_pages: Some("pages".into()), ..ServerConfig::default() });
+        fs::create_dir(instance.dir.join("pages")).unwrap();
+        fs::write(instance.dir.join("pages/404.html"), "<h1>No such note</h1>").unwrap();
+
+        let page = instance.request_with("GET", "/notes/missing.md", &["Accept: text/html"], b"").await;
+        assert_eq!((page.status, page.text()), (404, "<h1>No such note</h1>"));
+        assert!(page.header("Content-Type").unwrap().starts_with("text/html"));
+
+        // Other clients, and errors without a page, get the plain bodies.
+        let plain = instance.get("/notes/missing.md").await;
+        assert_eq!((plain.status, plain.header("Content-Type")), (404, Some("text/plain")));
+
+        let fallback = instance.request_with("GET", "/list?end=10", &["Accept: text/html"], b"").await;
+        assert_eq!(fallback.status, 500);
+        assert!(fallback.text().contains("Missing query parameter"));
+    }
+
+    #[tokio::test]
+    async fn deleted_notes_are_gone_instead_of_missing() {
+        let instance = Instance::with_config("tombstones", ServerConfig::default());
+        fs::write(instance.note("a.md"), "a").unwrap();
+        instance.request("DELETE", "/notes/a.md", b"").await;
+
+        assert_eq!(instance.get("/notes/a.md").await.status, 410);
+        assert_eq!(instance.get("/notes/never.md").await.status, 404);
+
+        // Writing the note again brings it back.
+        instance.post("/notes/a.md", "again").await;
+        assert_eq!(instance.get("/notes/a.md").await.status, 200);
+
+        let forgetful = Instance::with_config("tombstones-off", ServerConfig { tombstone_window: 0, ..ServerConfig::default() });
+        fs::write(forgetful.note("a.md"), "a").unwrap();
+        forgetful.request("DELETE", "/notes/a.md", b"").await;
+        assert_eq!(forgetful.get("/notes/a.md").await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn custom_auth_header_authenticates() {
+        let instance = Instance::with_config("auth-header", ServerConfig { auth_header: "X-Pyne-Token".to_owned(), ..ServerConfig::default() });
+        let token = testing::TOKEN;
+
+        assert_eq!(instance.get("/status").await.status, 200);
+        let lowercase = instance.raw(format!("GET /status HTTP/1.1\r\nx-pyne-token: {token}\r\n\r\n").as_bytes()).await;
+        assert_eq!(lowercase.status, 200);
+
+        // The default header isn't accepted anymore.
+        let default = instance.raw(format!("GET /status HTTP/1.1\r\nAuthorization: {token}\r\n\r\n").as_bytes()).await;
+        assert_eq!(default.status, 401);
+    }
+
+    #[tokio::test]
+    async fn truncate_shrinks_or_clears_a_note() {
+        let instance = Instance::with_config("truncate", ServerConfig { note_cache_bytes: 1024, ..ServerConfig::default() });
+        fs::write(instance.note("log.md"), "0123456789").unwrap();
+        assert_eq!(instance.get("/notes/log.md").await.text(), "0123456789");
+
+        assert_eq!(instance.post("/notes/log.md?truncate=4", "").await.status, 200);
+        assert_eq!(instance.get("/notes/log.md").await.text(), "0123");
+
+        // Truncating never pads the note.
+        assert_eq!(instance.post("/notes/log.md?truncate=100", "").await.status, 200);
+        assert_eq!(fs::read_to_string(instance.note("log.md")).unwrap(), "0123");
+
+        assert_eq!(instance.post("/notes/log.md?truncate=0", "").await.status, 200);
+        assert_eq!(fs::metadata(instance.note("log.md")).unwrap().len(), 0);
+
+        assert_eq!(instance.post("/notes/missing.md?truncate=0", "").await.status, 404);
+        assert_eq!(instance.post("/notes/log.md?truncate=-1", "").await.status, 400);
+    }
+
+    #[tokio::test]
+    async fn tls_info_reports_the_configured_min_version() {
+        let instance = Instance::with_config("tls-info", ServerConfig { tls_min_version: "1.3".to_owned(), ..ServerConfig::default() });
+
+        let res = instance.get("/tls-info").await;
+        assert_eq!(res.json()["min_version"], "1.3");
+        assert_eq!(res.json()["versions"], serde_json::json!(["TLSv1_3"]));
+    }
+
+    #[tokio::test]
+    async fn extensionless_writes_get_md_appended_when_enabled() {
+        let instance = Instance::with_config("append-md", ServerConfig { append_md_extension: true, ..ServerConfig::default() });
+
+        let res = instance.post("/notes/idea", "an idea").await;
+        assert_eq!(res.status, 201);
+        assert_eq!(res.header("Location"), Some("/notes/idea.md"));
+        assert_eq!(res.header("Content-Location"), Some("/notes/idea.md"));
+        assert_eq!(fs::read_to_string(instance.note("idea.md")).unwrap(), "an idea");
+        assert!(!instance.note("idea").exists());
+
+        // Notes with an extension, and instances without the option, keep their names.
+        instance.post("/notes/list.txt", "").await;
+        assert!(instance.note("list.txt").exists());
+
+        let exact = Instance::with_config("append-md-off", ServerConfig::default());
+        exact.post("/notes/idea", "an idea").await;
+        assert!(exact.note("idea").exists());
+    }
+
+    #[tokio::test]
+    async fn empty_writes_are_refused_only_when_a_body_is_required() {
+        let instance = Instance::with_config("require-body", ServerConfig { require_body: true, ..ServerConfig::default() });
+
+        assert_eq!(instance.post("/notes/empty", "").await.status, 400);
+        assert!(!instance.note("empty").exists());
+        assert_eq!(instance.post("/notes/empty?empty=true", "").await.status, 201);
+        assert_eq!(fs::read(instance.note("empty")).unwrap(), b"");
+        assert!(instance.post("/notes/full", "content").await.status < 300);
+
+        let default = Instance::with_config("require-body-off", ServerConfig::default());
+        assert!(default.post("/notes/empty", "").await.status < 300);
+        assert_eq!(fs::read(default.note("empty")).unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn whoami_describes_the_authenticated_token() {
+        let instance = Instance::with_config("whoami", ServerConfig::default());
+
+        let res = instance.get("/whoami").await;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.json(), serde_json::json!({ "label": "default", "scope": "all", "namespace": null }));
+
+        let anonymous = instance.raw(b"GET /whoami HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(anonymous.status, 401);
+    }
+
+    #[tokio::test]
+    async fn if_match_only_returns_the_matching_version() {
+        let instance = Instance::with_config("if-match", ServerConfig::default());
+        fs::write(instance.note("a.md"), "version 1").unwrap();
+        let etag = note_etag(b"version 1");
+
+        let res = instance.request_with("GET", "/notes/a.md", &[&format!("If-Match: \"other\", {etag}")], b"").await;
+        assert_eq!((res.status, res.text()), (200, "version 1"));
+        assert_eq!(instance.request_with("GET", "/notes/a.md", &["If-Match: *"], b"").await.status, 200);
+
+        fs::write(instance.note("a.md"), "version 2").unwrap();
+        let stale = instance.request_with("GET", "/notes/a.md", &[&format!("If-Match: {etag}")], b"").await;
+        assert_eq!(stale.status, 412);
+        assert!(!stale.text().contains("version 2"));
+    }
+
+    #[tokio::test]
+    async fn list_reads_the_instance_notes_instead_of_the_working_directory() {
+        let instance = Instance::with_config("list-instance-dir", ServerConfig::default());
+        assert_ne!(std::env::current_dir().unwrap(), instance.dir.path());
+        fs::write(instance.note("only-here.md"), "").unwrap();
+
+        let res = instance.get("/list?start=0&end=10").await;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.json()["total"], 1);
+        assert_eq!(res.json()["notes"][0]["name"], "only-here.md");
+    }
+
+    #[tokio::test]
+    async fn clients_closing_early_are_logged_as_disconnects() {
+        testing::capture_logs();
+        let instance = Instance::with_config("closed-early", ServerConfig::default());
+        fs::write(instance.note("big.md"), "x".repeat(1 << 20)).unwrap();
+
+        // Read the start of the response and hang up, while the server is still sending it.
+        let (mut client, server) = tokio::io::duplex(1024);
+        let conn = tokio::spawn(handle_conn(server, "test".to_owned(), instance.state.clone()));
+        let request = format!("GET /notes/big.md HTTP/1.1\r\nHost: localhost\r\n{}: {}\r\n\r\n", instance.state.config.auth_header, testing::TOKEN);
+        client.write_all(request.as_bytes()).await.unwrap();
+        testing::read_until(&mut client, &mut Vec::new(), "HTTP/1.1 200").await;
+        drop(client);
+
+        let err = conn.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        log_conn_error(&err);
+        let logged = testing::logged(&err.to_string());
+        assert!(logged.iter().any(|(level, message)| *level == log::Level::Debug && message.starts_with("Client disconnected")));
+        assert!(logged.iter().all(|(level, _)| *level != log::Level::Error));
+    }
+
+    #[tokio::test]
+    async fn rename_many_rejects_the_batch_if_any_pair_is_invalid() {
+        let instance = Instance::with_config("rename-many", ServerConfig::default());
+        fs::write(instance.note("a.md"), "a").unwrap();
+        fs::write(instance.note("b.md"), "b").unwrap();
+
+        let batch = r#"[{ "from": "a.md", "to": "c.md" }, { "from": "b.md", "to": "../escaped.md" }]"#;
+        assert_eq!(instance.post("/rename-many", batch).await.status, 400);
+        assert!(instance.note("a.md").exists() && instance.note("b.md").exists());
+        assert!(!instance.note("c.md").exists() && !instance.dir.join("escaped.md").exists());
+
+        let missing = r#"[{ "from": "a.md", "to": "c.md" }, { "from": "missing.md", "to": "d.md" }]"#;
+        assert_eq!(instance.post("/rename-many", missing).await.status, 400);
+        assert!(instance.note("a.md").exists() && !instance.note("c.md").exists());
+
+        let valid = r#"[{ "from": "a.md", "to": "c.md" }, { "from": "b.md", "to": "d.md" }]"#;
+        let res = instance.post("/rename-many", valid).await;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.json()[1], serde_json::json!({ "from": "b.md", "to": "d.md", "renamed": true }));
+        assert_eq!(fs::read_to_string(instance.note("c.md")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(instance.note("d.md")).unwrap(), "b");
+    }
+
+    #[test]
+    fn listen_addr_takes_any_host() {
+        assert_eq!(listen_addr("0.0.0.0", 8443).unwrap(), "0.0.0.0:8443".parse().unwrap());
+        assert_eq!(listen_addr("::1", 8443).unwrap(), "[::1]:8443".parse().unwrap());
+        assert!(listen_addr("localhost", 8443).unwrap().ip().is_loopback());
+
+        let err = listen_addr("not a host.invalid", 8443).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("Invalid host `not a host.invalid`"));
+    }
+
+    #[tokio::test]
+    async fn list_if_changed_skips_unchanged_listings() {
+        let instance = Instance::with_config("if-changed", ServerConfig::default());
+        fs::write(instance.note("a.md"), "a").unwrap();
+
+        let res = instance.get("/list?start=0&end=10").await;
+        let token = res.header("X-State-Token").unwrap().to_owned();
+
+        let unchanged = instance.get(&format!("/list?start=0&end=10&if_changed={token}")).await;
+        assert_eq!((unchanged.status, unchanged.header("X-State-Token")), (304, Some(token.as_str())));
+        assert!(unchanged.body.is_empty());
+
+        instance.post("/notes/b.md", "b").await;
+        let changed = instance.get(&format!("/list?start=0&end=10&if_changed={token}")).await;
+        assert_eq!(changed.status, 200);
+        assert_eq!(changed.json()["total"], 2);
+        let new_token = changed.header("X-State-Token").unwrap();
+        assert_ne!(new_token, token);
+        assert_eq!(instance.get(&format!("/list?start=0&end=10&if_changed={new_token}")).await.status, 304);
+    }
+
+    #[tokio::test]
+    async fn dotfiles_are_only_served_when_enabled() {
+        for serve_hidden in [false, true] {
+            let instance = Instance::with_config("dotfiles", ServerConfig { serve_hidden, ..ServerConfig::default() });
+            fs::write(instance.note(".secret.md"), "hidden").unwrap();
+            fs::create_dir(instance.note(".git")).unwrap();
+            fs::write(instance.note(".git/config"), "hidden").unwrap();
+            fs::write(instance.note("visible.md"), "shown").unwrap();
+
+            let dotfile = instance.get("/notes/.secret.md").await;
+            let nested = instance.get("/notes/.git/config").await;
+            let listed = instance.get("/list?start=0&end=10").await.json()["total"].clone();
+            if serve_hidden {
+                assert_eq!((dotfile.status, dotfile.text()), (200, "hidden"));
+                assert_eq!(nested.status, 200);
+                assert_eq!(listed, 3);
+            } else {
+                assert_eq!((dotfile.status, nested.status), (404, 404));
+                assert_eq!(listed, 1);
+            }
+            assert_eq!(instance.get("/notes/visible.md").await.status, 200);
+        }
+    }
+
+    #[test]
+    fn auth_token_comes_from_the_environment_or_the_config() {
+        let config = ServerConfig { token: Some("from-config".to_owned()), ..ServerConfig::default() };
+        assert_eq!(auth_token(Some("from-env".to_owned()), &config).unwrap(), "from-env");
+        assert_eq!(auth_token(Some(" ".to_owned()), &config).unwrap(), "from-config");
+        assert_eq!(auth_token(None, &config).unwrap(), "from-config");
+
+        let err = auth_token(None, &ServerConfig::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn writing_a_new_note_is_created_and_an_existing_one_ok() {
+        let instance = Instance::with_config("created", ServerConfig::default());
+
+        let created = instance.post("/notes/new%20note.md", "first").await;
+        assert_eq!(created.status, 201);
+        assert_eq!(created.header("Location"), Some("/notes/new%20note.md"));
+
+        let updated = instance.post("/notes/new%20note.md", "second").await;
+        assert_eq!(updated.status, 200);
+        assert_eq!(updated.header("Location"), None);
+        assert_eq!(fs::read_to_string(instance.note("new note.md")).unwrap(), "second");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unreadable_notes_are_forbidden_instead_of_missing() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let instance = Instance::with_config("unreadable", ServerConfig::default());
+        fs::write(instance.note("locked.md"), "secret").unwrap();
+        fs::set_permissions(instance.note("locked.md"), fs::Permissions::from_mode(0o000)).unwrap();
+
+        // Permissions don't apply to root, so there's nothing to test.
+        if fs::read(instance.note("locked.md")).is_ok() {
+            return;
+        }
+
+        let res = instance.get("/notes/locked.md").await;
+        assert_eq!(res.status, 403);
+        assert!(res.text().ends_with("Note is not readable."));
+        assert_eq!(instance.get("/notes/missing.md").await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn status_reports_the_package_version() {
+        let instance = Instance::with_config("status", ServerConfig::default());
+
+        let status = instance.get("/status").await.json();
+        assert_eq!(status["version"], env!("CARGO_PKG_VERSION"));
+        assert!(!status["git_commit"].as_str().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsupported_note_methods_are_not_allowed() {
+        let instance = Instance::with_config("methods", ServerConfig::default());
+
+        assert_eq!(instance.post("/notes/a.md", "a").await.status, 201);
+        assert_eq!(instance.get("/notes/a.md").await.status, 200);
+        assert_eq!(instance.request("DELETE", "/notes/a.md", b"").await.status, 200);
+
+        for method in ["PUT", "PATCH", "BREW"] {
+            let res = instance.request(method, "/notes/a.md", b"a").await;
+            assert_eq!(res.status, 405, "{method}");
+            assert_eq!(res.header("Allow"), Some("GET, HEAD, POST, DELETE, OPTIONS"));
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_the_connections_to_finish() {
+        let finished = Arc::new(AtomicUsize::new(0));
+        let mut connections = JoinSet::new();
+        for i in 1..=3 {
+            let finished = finished.clone();
+            connections.spawn(async move {
+                tokio::time::sleep(Duration::from_millis(100 * i)).await;
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        finish_connections(connections).await;
+        assert_eq!(finished.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn stalled_requests_time_out() {
+        let instance = Instance::with_config("stalled", ServerConfig { read_timeout: 1, ..ServerConfig::default() });
+
+        // Send half a request and keep the connection open.
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"GET /notes/a.md HTTP/1.1\r\nHost: loc").await.unwrap();
+        let started = Instant::now();
+
+        let result = tokio::time::timeout(Duration::from_secs(5), handle_conn(server, "test".to_owned(), instance.state.clone())).await;
+        let err = result.expect("Stalled request was never given up on.").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        drop(client);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unique_writes_get_numbered_suffixes() {
+        let instance = Arc::new(Instance::with_config("unique", ServerConfig::default()));
+
+        let first = instance.post("/notes/capture.md?unique=true", "first").await;
+        let second = instance.post("/notes/capture.md?unique=true", "second").await;
+        assert_eq!((first.status, first.header("Location")), (201, Some("/notes/capture.md")));
+        assert_eq!((second.status, second.header("Location")), (201, Some("/notes/capture-2.md")));
+        assert_eq!(fs::read_to_string(instance.note("capture.md")).unwrap(), "first");
+        assert_eq!(fs::read_to_string(instance.note("capture-2.md")).unwrap(), "second");
+
+        // Concurrent writers never claim the same name.
+        let mut writes = JoinSet::new();
+        for i in 0..8 {
+            let instance = instance.clone();
+            writes.spawn(async move { instance.post("/notes/race.md?unique=true", &i.to_string()).await.header("Location").unwrap().to_owned() });
+        }
+        let mut locations = Vec::new();
+        while let Some(location) = writes.join_next().await {
+            locations.push(location.unwrap());
+        }
+        locations.sort();
+        locations.dedup();
+        assert_eq!(locations.len(), 8);
+    }
+
+    #[tokio::test]
+    async fn mixed_case_auth_headers_are_accepted() {
+        let instance = Instance::with_config("mixed-case", ServerConfig::default());
+        fs::write(instance.note("a.md"), "a").unwrap();
+
+        let res = instance.raw(format!("GET /notes/a.md HTTP/1.1\r\nhOsT: localhost\r\naUtHoRiZaTiOn: {}\r\n\r\n", testing::TOKEN).as_bytes()).await;
+        assert_eq!((res.status, res.text()), (200, "a"));
+    }
+
+    #[tokio::test]
+    async fn requests_are_logged_with_the_sent_status_and_size() {
+        testing::capture_logs();
+        let instance = Instance::with_config("access-log", ServerConfig::default());
+        fs::write(instance.note("access-log.md"), "hello").unwrap();
+
+        let res = instance.get("/notes/access-log.md").await;
+        let missing = instance.get("/notes/access-log-missing.md").await;
+        let head = instance.request("HEAD", "/notes/access-log.md", b"").await;
+
+        let logged = testing::logged("/notes/access-log");
+        let line = |line: &str| logged.iter().any(|(level, message)| *level == log::Level::Info && message == line);
+        assert!(line(&format!("test \"GET /notes/access-log.md\" {} 5", res.status)), "{logged:?}");
+        assert!(line(&format!("test \"GET /notes/access-log-missing.md\" {} {}", missing.status, missing.body.len())), "{logged:?}");
+        assert!(line(&format!("test \"HEAD /notes/access-log.md\" {} 0", head.status)), "{logged:?}");
+    }
+
+    #[tokio::test]
+    async fn note_metadata_headers_match_the_file() {
+        let instance = Instance::with_config("note-headers", ServerConfig::default());
+        let path = instance.note("a.md");
+        fs::write(&path, "12345").unwrap();
+        fs::File::options().write(true).open(&path).unwrap().set_modified(UNIX_EPOCH + Duration::from_secs(784_111_777)).unwrap();
+
+        let res = instance.get("/notes/a.md").await;
+        assert_eq!(res.header("X-Note-Size"), Some("5"));
+        assert_eq!(res.header("X-Note-Modified"), Some("Sun, 06 Nov 1994 08:49:37 GMT"));
+
+        // Not every filesystem records when a file was created.
+        let created = fs::metadata(&path).unwrap().created().ok().map(http_date);
+        assert_eq!(res.header("X-Note-Created"), created.as_deref());
+    }
+
+    #[tokio::test]
+    async fn head_sends_the_length_without_the_body() {
+        let instance = Instance::with_config("head", ServerConfig::default());
+        fs::write(instance.note("a.md"), "hello world").unwrap();
+
+        let raw = instance.exchange(format!("HEAD /notes/a.md HTTP/1.1\r\nHost: localhost\r\n{}: {}\r\n\r\n", instance.state.config.auth_header, testing::TOKEN).as_bytes()).await;
+        let raw = String::from_utf8(raw).unwrap();
+        assert!(raw.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(raw.contains("\r\nContent-Length: 11\r\n"));
+        assert!(raw.ends_with("\r\n\r\n"), "{raw:?}");
+        assert!(!raw.contains("hello world"));
+
+        assert_eq!(instance.request("HEAD", "/status", b"").await.status, 200);
+        let missing = instance.request("HEAD", "/notes/missing.md", b"").await;
+        assert_eq!((missing.status, missing.body.len()), (404, 0));
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_reaped_and_counted() {
+        let config = ServerConfig { idle_timeout: 1, read_timeout: 60, ..ServerConfig::default() };
+        let instance = Instance::with_config("idle", config);
+        let state = instance.state.clone();
+
+        // Connect, like the accept loop does, and never send anything.
+        let (client, server) = tokio::io::duplex(1024);
+        let activity = state.idle.register();
+        let activity_id = activity.id();
+        let conn = tokio::spawn(handle_conn(TrackedStream::new(server, activity), "test".to_owned(), state.clone()));
+        state.idle.attach(activity_id, conn.abort_handle());
+        let reaper = tokio::spawn(reap_idle(state.clone()));
+
+        let reaped = tokio::time::timeout(Duration::from_secs(5), conn).await.expect("Idle connection was never reaped.");
+        assert!(reaped.unwrap_err().is_cancelled());
+        assert_eq!(state.metrics.snapshot().reaped, 1);
+        reaper.abort();
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn preflight_requests_need_no_credentials() {
+        let instance = Instance::with_config("preflight", ServerConfig { auth_header: "X-Pyne-Token".to_owned(), ..ServerConfig::default() });
+
+        let preflight = b"OPTIONS /notes/a.md HTTP/1.1\r\nHost: localhost\r\nOrigin: https://editor.example\r\nAccess-Control-Request-Method: POST\r\n\r\n";
+        let res = instance.raw(preflight).await;
+        assert_eq!(res.status, 204);
+        assert_eq!(res.header("Access-Control-Allow-Methods"), Some("GET, HEAD, POST, DELETE, OPTIONS"));
+        assert_eq!(res.header("Access-Control-Allow-Headers"), Some("Authorization, Content-Type, If-Match, If-None-Match, X-Pyne-Token"));
+        assert_eq!(res.header("Access-Control-Max-Age"), Some("86400"));
+        assert_eq!(res.header("Access-Control-Allow-Origin"), Some("*"));
+
+        // Other requests still need the token.
+        let write = instance.raw(b"POST /notes/a.md HTTP/1.1\r\nHost: localhost\r\nOrigin: https://editor.example\r\nContent-Length: 1\r\n\r\na").await;
+        assert_eq!(write.status, 401);
+    }
+
+    #[tokio::test]
+    async fn ranged_writes_patch_and_complete_notes() {
+        let instance = Instance::with_config("ranged-writes", ServerConfig::default());
+        let range = |range: &str| format!("Content-Range: bytes {range}");
+
+        // Write a segment in the middle of an existing note.
+        fs::write(instance.note("digits.txt"), "0123456789").unwrap();
+        let middle = instance.request_with("POST", "/notes/digits.txt", &[&range("3-4/10")], b"ab").await;
+        assert_eq!(middle.status, 204);
+        assert_eq!(fs::read_to_string(instance.note("digits.txt")).unwrap(), "012ab56789");
+
+        // Upload a new note in two pieces.
+        let first = instance.request_with("POST", "/notes/upload.txt", &[&range("0-5/11")], b"hello ").await;
+        assert_eq!(first.status, 201);
+        let last = instance.request_with("POST", "/notes/upload.txt", &[&range("6-10/11")], b"world").await;
+        assert_eq!(last.status, 204);
+        assert_eq!(fs::read_to_string(instance.note("upload.txt")).unwrap(), "hello world");
+
+        // Ranges that don't match the body or leave a gap are refused.
+        for bad in ["0-3/11", "20-24/30", "5-4/11", "9-13/11"] {
+            let res = instance.request_with("POST", "/notes/upload.txt", &[&range(bad)], b"abcde").await;
+            assert_eq!(res.status, 416, "{bad}");
+        }
+        assert_eq!(fs::read_to_string(instance.note("upload.txt")).unwrap(), "hello world");
+    }
+
+    #[tokio::test]
+    async fn responses_allow_the_configured_origins() {
+        let instance = Instance::with_config("cors-origins", ServerConfig { cors_origins: vec!["https://editor.example".to_owned()], ..ServerConfig::default() });
+
+        let allowed = instance.request_with("GET", "/status", &["Origin: https://editor.example"], b"").await;
+        assert_eq!(allowed.header("Access-Control-Allow-Origin"), Some("https://editor.example"));
+        assert_eq!(allowed.header("Vary"), Some("Origin"));
+
+        let other = instance.request_with("GET", "/status", &["Origin: https://evil.example"], b"").await;
+        assert_eq!((other.status, other.header("Access-Control-Allow-Origin")), (200, None));
+    }
+
+    #[tokio::test]
+    async fn digest_header_matches_the_body() {
+        use base64::{Engine, engine::general_purpose::STANDARD};
+
+        let instance = Instance::with_config("digest", ServerConfig { note_digests: true, ..ServerConfig::default() });
+        fs::write(instance.note("a.md"), "hello").unwrap();
+
+        let res = instance.get("/notes/a.md").await;
+        let encoded = res.header("Digest").and_then(|digest| digest.strip_prefix("sha-256=")).unwrap();
+        assert_eq!(STANDARD.decode(encoded).unwrap(), digest(&SHA256, &res.body).as_ref());
+        assert_eq!(encoded, "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=");
+
+        let disabled = Instance::with_config("digest-off", ServerConfig::default());
+        fs::write(disabled.note("a.md"), "hello").unwrap();
+        assert_eq!(disabled.get("/notes/a.md").await.header("Digest"), None);
+    }
+
+    #[tokio::test]
+    async fn list_reports_the_total_and_the_window() {
+        let instance = Instance::with_config("list-window", ServerConfig::default());
+        for i in 0..7 {
+            fs::write(instance.note(&format!("{i}.md")), "").unwrap();
+        }
+        let names = |listing: &serde_json::Value| -> Vec<String> {
+            listing["notes"].as_array().unwrap().iter().map(|note| note["name"].as_str().unwrap().to_owned()).collect()
+        };
+
+        let page = instance.get("/list?start=2&end=5").await.json();
+        assert_eq!((page["total"].clone(), page["start"].clone(), page["end"].clone()), (7.into(), 2.into(), 5.into()));
+        assert_eq!(names(&page), ["2.md", "3.md", "4.md"]);
+
+        // The end of the last page is where the notes ran out.
+        let last = instance.get("/list?start=5&end=10").await.json();
+        assert_eq!((last["total"].clone(), last["end"].clone()), (7.into(), 7.into()));
+        assert_eq!(names(&last), ["5.md", "6.md"]);
+
+        let past = instance.get("/list?start=10&end=20").await.json();
+        assert_eq!((past["start"].clone(), past["end"].clone()), (10.into(), 10.into()));
+        assert!(names(&past).is_empty());
+    }
+
+    /// Get the names of the notes a `/list` request returns.
+    async fn listed(instance: &Instance, target: &str) -> Vec<String> {
+        let listing = instance.get(target).await.json();
+        listing["notes"].as_array().unwrap().iter().map(|note| note["name"].as_str().unwrap().to_owned()).collect()
+    }
+
+    #[tokio::test]
+    async fn list_sorts_by_the_requested_order() {
+        let instance = Instance::with_config("list-sort", ServerConfig::default());
+        for (name, age) in [("b.md", 300), ("a.md", 100), ("c.md", 200)] {
+            write_aged(&instance.note(name), age);
+        }
+
+        assert_eq!(listed(&instance, "/list?start=0&end=10").await, ["a.md", "b.md", "c.md"]);
+        assert_eq!(listed(&instance, "/list?start=0&end=10&sort=-name").await, ["c.md", "b.md", "a.md"]);
+        assert_eq!(listed(&instance, "/list?start=0&end=10&sort=mtime").await, ["b.md", "c.md", "a.md"]);
+        assert_eq!(listed(&instance, "/list?start=0&end=10&sort=-mtime").await, ["a.md", "c.md", "b.md"]);
+
+        // Pages of the same order never overlap.
+        let first = listed(&instance, "/list?start=0&end=2&sort=-mtime").await;
+        let second = listed(&instance, "/list?start=2&end=4&sort=-mtime").await;
+        assert_eq!([first, second].concat(), ["a.md", "c.md", "b.md"]);
+
+        assert_eq!(instance.get("/list?start=0&end=10&sort=size").await.status, 400);
+    }
+
+    #[tokio::test]
+    async fn collections_keep_their_notes_apart() {
+        let collections = std::collections::HashMap::from([("work".to_owned(), PathBuf::from("work")), ("home".to_owned(), PathBuf::from("home"))]);
+        let instance = Instance::with_config("collections", ServerConfig { collections, ..ServerConfig::default() });
+        fs::create_dir(instance.dir.join("work")).unwrap();
+        fs::create_dir(instance.dir.join("home")).unwrap();
+
+        let work = instance.post("/c/work/notes/todo.md", "ship it").await;
+        assert_eq!((work.status, work.header("Location")), (201, Some("/c/work/notes/todo.md")));
+        instance.post("/c/home/notes/todo.md", "water plants").await;
+
+        assert_eq!(fs::read_to_string(instance.dir.join("work/todo.md")).unwrap(), "ship it");
+        assert_eq!(fs::read_to_string(instance.dir.join("home/todo.md")).unwrap(), "water plants");
+        assert!(!instance.note("todo.md").exists());
+
+        assert_eq!(instance.get("/c/work/notes/todo.md").await.text(), "ship it");
+        assert_eq!(instance.get("/c/home/notes/todo.md").await.text(), "water plants");
+        assert_eq!(instance.get("/notes/todo.md").await.status, 404);
+        assert_eq!(instance.get("/c/games/notes/todo.md").await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn large_responses_are_warned_about() {
+        testing::capture_logs();
+        let instance = Instance::with_config("large-response", ServerConfig { large_response_bytes: 1024, ..ServerConfig::default() });
+        fs::write(instance.note("large-response.md"), "x".repeat(4096)).unwrap();
+        fs::write(instance.note("small-response.md"), "x").unwrap();
+
+        instance.get("/notes/large-response.md").await;
+        instance.get("/notes/small-response.md").await;
+
+        let warned = testing::logged("heavy response");
+        let warned_for = |path: &str| warned.iter().filter(|(level, message)| *level == log::Level::Warn && message.starts_with(path)).count();
+        assert_eq!(warned_for("/notes/large-response.md: heavy response, took "), 1);
+        assert!(warned.iter().any(|(_, message)| message.starts_with("/notes/large-response.md") && message.ends_with("ms to send 4096 bytes")));
+        assert_eq!(warned_for("/notes/small-response.md"), 0);
+    }
 }
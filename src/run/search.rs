@@ -0,0 +1,194 @@
+use std::{fs, io, path::Path};
+
+/// Notes larger than this are skipped when searching.
+const MAX_SEARCH_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Most matches collected by a search, more aren't counted.
+pub(crate) const MAX_SEARCH_MATCHES: usize = 10_000;
+
+/// A line of a note that contains the search term.
+#[derive(Debug)]
+pub(crate) struct SearchMatch {
+    pub name: String,
+    /// Line number, starting at 1.
+    pub line: usize,
+    pub snippet: String,
+}
+
+impl SearchMatch {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({ "name": self.name, "line": self.line, "snippet": self.snippet })
+    }
+}
+
+/// Search the notes in a directory (and its subdirectories) for lines containing a term, ignoring case.
+/// Collects up to `MAX_SEARCH_MATCHES` matches, which are paged by the caller.
+pub fn search(dir: &Path, term: &str, include_hidden: bool, snippet_len: usize) -> io::Result<Vec<SearchMatch>> {
+    let mut notes = Vec::new();
+    collect_notes(dir, dir, include_hidden, &mut notes)?;
+
+    // Search in a stable order, so pages of matches line up.
+    notes.sort();
+
+    let term = term.to_lowercase();
+    let root = fs::canonicalize(dir)?;
+    let mut matches = Vec::new();
+
+    for name in notes {
+        // Symlinks may not lead outside the notes directory, just like when reading notes.
+        let Ok(path) = fs::canonicalize(dir.join(&name)) else {
+            continue;
+        };
+        if !path.starts_with(&root) || fs::metadata(&path).map_or(true, |meta| meta.len() > MAX_SEARCH_FILE_SIZE) {
+            continue;
+        }
+        // Binary files aren't notes worth searching.
+        let Ok(content) = fs::read_to_string(&path) else {
+            continue;
+        };
+
+        for (i, line) in content.lines().enumerate() {
+            if matches.len() >= MAX_SEARCH_MATCHES {
+                return Ok(matches);
+            }
+            let line = line.trim();
+            if let Some(found) = find_ignore_case(line, &term) {
+                matches.push(SearchMatch { name: name.clone(), line: i + 1, snippet: snippet(line, found, snippet_len) });
+            }
+        }
+    }
+    Ok(matches)
+}
+
+/// Find a lowercase term in a line ignoring case, as the position and length of the match in characters.
+fn find_ignore_case(line: &str, term: &str) -> Option<(usize, usize)> {
+    // Lowercasing can change the length of characters, so remember which character each byte came from.
+    let mut lowered = String::with_capacity(line.len());
+    let mut origins = Vec::with_capacity(line.len());
+    for (i, c) in line.chars().enumerate() {
+        lowered.extend(c.to_lowercase());
+        origins.resize(lowered.len(), i);
+    }
+
+    let start = lowered.find(term)?;
+    let end = start + term.len();
+    if start == end {
+        return Some((0, 0));
+    }
+    Some((origins[start], origins[end - 1] + 1 - origins[start]))
+}
+
+/// Cut a window of at most `max_len` characters out of a line, centred on a match, with ellipses where it was cut.
+fn snippet(line: &str, (start, len): (usize, usize), max_len: usize) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    if chars.len() <= max_len {
+        return line.to_owned();
+    }
+
+    // Leave room for the ellipses, unless there's barely room for the line itself.
+    let ellipses = max_len > 2;
+    let window = if ellipses { max_len - 2 } else { max_len };
+
+    // Keep the window inside the line when the match is near one of its ends.
+    let from = (start + len / 2).saturating_sub(window / 2).min(chars.len() - window);
+    let mut snippet = String::with_capacity(window + 6);
+    if ellipses && from > 0 {
+        snippet.push('…');
+    }
+    snippet.extend(&chars[from..from + window]);
+    if ellipses && from + window < chars.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// Collect the paths of all notes below a directory, relative to the notes directory.
+fn collect_notes(root: &Path, dir: &Path, include_hidden: bool, notes: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !include_hidden && entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        // Symlinked directories aren't followed, they could loop.
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_notes(root, &path, include_hidden, notes)?;
+        } else if let Ok(name) = path.strip_prefix(root) {
+            notes.push(name.to_string_lossy().replace('\\', "/"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::testing::TempDir;
+
+    fn notes(name: &str, notes: &[(&str, &str)]) -> TempDir {
+        let dir = TempDir::new(name);
+        for (name, content) in notes {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn search_finds_lines_with_the_term() {
+        let dir = notes("search", &[("a.md", "nothing here\nA Pyne note\n"), ("b.md", "no match"), ("c.md", "pyne\n\nagain PYNE")]);
+        let matches = search(dir.path(), "pyne", false, 200).unwrap();
+
+        let found: Vec<(&str, usize, &str)> = matches.iter().map(|found| (found.name.as_str(), found.line, found.snippet.as_str())).collect();
+        assert_eq!(found, [("a.md", 2, "A Pyne note"), ("c.md", 1, "pyne"), ("c.md", 3, "again PYNE")]);
+        assert!(search(dir.path(), "absent", false, 200).unwrap().is_empty());
+    }
+
+    #[test]
+    fn search_skips_binary_and_hidden_notes() {
+        let dir = notes("search-skip", &[("a.bin", "pyne \u{0}"), (".hidden.md", "pyne")]);
+        fs::write(dir.join("b.bin"), b"pyne \xff").unwrap();
+
+        let names: Vec<String> = search(dir.path(), "pyne", false, 200).unwrap().into_iter().map(|found| found.name).collect();
+        assert_eq!(names, ["a.bin"]);
+        assert_eq!(search(dir.path(), "pyne", true, 200).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn snippet_is_centred_on_the_match() {
+        let line = format!("{}needle{}", "a".repeat(300), "b".repeat(300));
+        let dir = notes("search-snippet", &[("long.md", &line)]);
+
+        let found = &search(dir.path(), "NEEDLE", false, 200).unwrap()[0].snippet;
+        assert_eq!(found.chars().count(), 200);
+        assert!(found.starts_with('…') && found.ends_with('…'));
+
+        let (before, after) = found.split_once("needle").unwrap();
+        assert_eq!(before.chars().count(), after.chars().count());
+
+        // Matches near the ends of a line keep the window inside it.
+        assert_eq!(snippet("needle and more", (0, 6), 8), "needle…");
+        assert_eq!(snippet("more and needle", (9, 6), 8), "…needle");
+        assert_eq!(snippet("short", (0, 5), 8), "short");
+    }
+
+    #[test]
+    fn snippet_keeps_multibyte_characters_whole() {
+        let line = format!("{}needle{}", "é".repeat(500), "日本".repeat(250));
+        let dir = notes("search-utf8", &[("long.md", &line)]);
+
+        for len in [20, 21, 150] {
+            let found = &search(dir.path(), "needle", false, len).unwrap()[0].snippet;
+            assert!(found.chars().count() <= len);
+            assert!(found.contains("needle"));
+            assert!(found.trim_matches('…').replace("needle", "").chars().all(|c| ['é', '日', '本'].contains(&c)));
+        }
+    }
+
+    #[test]
+    fn find_ignore_case_counts_characters() {
+        assert_eq!(find_ignore_case("Ünïcode PYNE", "pyne"), Some((8, 4)));
+        assert_eq!(find_ignore_case("İx", "x"), Some((1, 1)));
+        assert_eq!(find_ignore_case("pyne", "note"), None);
+    }
+}
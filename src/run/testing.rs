@@ -0,0 +1,219 @@
+//! Helpers shared by the tests of the server modules.
+
+use std::{fs, path::{Path, PathBuf}, sync::{Arc, Mutex, Once, atomic::{AtomicUsize, Ordering}}, time::Duration};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use super::{State, config::ServerConfig, handle_conn};
+
+/// Directory for a single test, removed with everything in it when dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create an empty directory, unique to this test run.
+    pub fn new(name: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let id = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let dir = std::env::temp_dir().join(format!("pyne-test-{}-{id}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Auth token of test instances.
+pub(crate) const TOKEN: &str = "test-token";
+
+/// Server instance in a temporary directory, with requests handled over an in-memory stream.
+pub(crate) struct Instance {
+    pub dir: TempDir,
+    pub state: Arc<State>,
+}
+
+impl Instance {
+    /// Create an instance, with the paths in the config relative to its directory.
+    pub fn with_config(name: &str, mut config: ServerConfig) -> Self {
+        let dir = TempDir::new(name);
+        fs::create_dir_all(dir.join("notes")).unwrap();
+        config.resolve_paths(dir.path());
+        let state = Arc::new(State::new(dir.path(), config, TOKEN.to_owned()).unwrap());
+        Instance { dir, state }
+    }
+
+    /// Path of a note in the notes directory.
+    pub fn note(&self, name: &str) -> PathBuf {
+        self.dir.join("notes").join(name)
+    }
+
+    /// Send a raw request and read the whole response.
+    pub async fn raw(&self, request: &[u8]) -> Response {
+        Response::parse(&self.exchange(request).await)
+    }
+
+    /// Send a raw request and read the bytes of the response.
+    pub async fn exchange(&self, request: &[u8]) -> Vec<u8> {
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let conn = tokio::spawn(handle_conn(server, "test".to_owned(), self.state.clone()));
+
+        client.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        conn.await.unwrap().unwrap();
+        response
+    }
+
+    /// Send an authorized request, with extra headers given as `Name: value` lines.
+    pub async fn request_with(&self, method: &str, target: &str, headers: &[&str], body: &[u8]) -> Response {
+        let mut head = format!("{method} {target} HTTP/1.1\r\nHost: localhost\r\n{}: {TOKEN}\r\n", self.state.config.auth_header);
+        for header in headers {
+            head.push_str(&format!("{header}\r\n"));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+        self.raw(&[head.as_bytes(), body].concat()).await
+    }
+
+    pub async fn request(&self, method: &str, target: &str, body: &[u8]) -> Response {
+        self.request_with(method, target, &[], body).await
+    }
+
+    pub async fn get(&self, target: &str) -> Response {
+        self.request("GET", target, b"").await
+    }
+
+    pub async fn post(&self, target: &str, body: &str) -> Response {
+        self.request("POST", target, body.as_bytes()).await
+    }
+}
+
+/// Response read back from a test instance, with chunked bodies reassembled.
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn parse(raw: &[u8]) -> Self {
+        let end = raw.windows(4).position(|window| window == b"\r\n\r\n").expect("Response has no end of headers.");
+        let head = std::str::from_utf8(&raw[..end]).unwrap();
+        let mut lines = head.split("\r\n");
+
+        let status = lines.next().unwrap().split_whitespace().nth(1).unwrap().parse().unwrap();
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.to_owned(), value.trim().to_owned()))
+            .collect();
+        let mut response = Response { status, headers, body: raw[end + 4..].to_vec() };
+
+        if response.header("Transfer-Encoding") == Some("chunked") {
+            response.body = dechunk(&response.body);
+        }
+        response
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> &str {
+        std::str::from_utf8(&self.body).unwrap()
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+/// Reassemble a chunked body.
+pub(crate) fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut content = Vec::new();
+
+    loop {
+        let line = body.windows(2).position(|window| window == b"\r\n").expect("Chunk has no size.");
+        let size = usize::from_str_radix(std::str::from_utf8(&body[..line]).unwrap(), 16).unwrap();
+        if size == 0 {
+            return content;
+        }
+        content.extend_from_slice(&body[line + 2..line + 2 + size]);
+        body = &body[line + 2 + size + 2..];
+    }
+}
+
+/// Create a named pipe, reads of which block until something is written to it.
+#[cfg(unix)]
+pub(crate) fn fifo(path: &Path) {
+    let status = std::process::Command::new("mkfifo").arg(path).status().unwrap();
+    assert!(status.success());
+}
+
+/// Wait for a condition to hold, polling it for a few seconds.
+pub(crate) async fn eventually(condition: impl Fn() -> bool) -> bool {
+    for _ in 0..100 {
+        if condition() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    condition()
+}
+
+/// Read from a stream until what was received contains `needle`, giving up after a few seconds.
+pub(crate) async fn read_until<R: AsyncRead + Unpin>(stream: &mut R, received: &mut Vec<u8>, needle: &str) {
+    let read = tokio::time::timeout(Duration::from_secs(5), async {
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(received).contains(needle) {
+            let read = stream.read(&mut buf).await.unwrap();
+            assert!(read > 0, "Stream ended before {needle:?} was received.");
+            received.extend_from_slice(&buf[..read]);
+        }
+    }).await;
+    assert!(read.is_ok(), "Timed out waiting for {needle:?}.");
+}
+
+/// Log records of every test, the logger is shared by the whole test binary.
+static LOGS: Mutex<Vec<(log::Level, String)>> = Mutex::new(Vec::new());
+
+struct CaptureLogger;
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        LOGS.lock().unwrap().push((record.level(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Start capturing the log records, at every level.
+pub(crate) fn capture_logs() {
+    static INSTALL: Once = Once::new();
+
+    INSTALL.call_once(|| {
+        log::set_logger(&CaptureLogger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+}
+
+/// Get the captured log records containing `needle`, which should be unique to the test.
+pub(crate) fn logged(needle: &str) -> Vec<(log::Level, String)> {
+    LOGS.lock().unwrap().iter().filter(|(_, message)| message.contains(needle)).cloned().collect()
+}
@@ -1,8 +1,14 @@
 use std::fs::File;
 use std::io::{self, BufReader};
 use std::path::Path;
+use std::sync::Arc;
 
-use tokio_rustls::rustls::{PrivateKey, Certificate};
+use tokio_rustls::rustls::{self, version, Certificate, PrivateKey, SupportedProtocolVersion, ALL_VERSIONS, DEFAULT_CIPHER_SUITES};
+use tokio_rustls::rustls::server::{NoServerSessionStorage, ServerSessionMemoryCache};
+
+use super::config::ServerConfig;
+
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&version::TLS13];
 
 pub fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
     let file = File::open(path)?;
@@ -17,12 +23,178 @@ pub fn load_keys(path: &Path) -> io::Result<PrivateKey> {
     let keyfile = File::open(path)?;
     let mut reader = io::BufReader::new(keyfile);
 
-    // Load and return a single private key.
-    match rustls_pemfile::read_one(&mut reader)? {
-        Some(rustls_pemfile::Item::PKCS8Key(key)) => Ok(PrivateKey(key)),
+    // Load and return the first private key, skipping any other entries.
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(io::Error::other(
+                "No PKCS8, RSA or EC private key found in the key file.".to_string(),
+            )),
+        }
+    }
+}
+
+/// Get the TLS versions enabled by a minimum version, either "1.2" or "1.3".
+pub fn protocol_versions(min_version: &str) -> io::Result<&'static [&'static SupportedProtocolVersion]> {
+    match min_version {
+        "1.2" => Ok(ALL_VERSIONS),
+        "1.3" => Ok(TLS13_ONLY),
         _ => Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Private key has to be the first entry in the key file.".to_string(),
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported minimum TLS version `{min_version}`, expected \"1.2\" or \"1.3\"."),
         )),
     }
 }
+
+/// Build the TLS settings of the server, for a certificate and its key.
+pub fn server_config(certs: Vec<Certificate>, key: PrivateKey, config: &ServerConfig) -> io::Result<rustls::ServerConfig> {
+    let mut tls = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(protocol_versions(&config.tls_min_version)?)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    // Let reconnecting clients resume their TLS session.
+    if config.tls_session_cache > 0 {
+        tls.session_storage = ServerSessionMemoryCache::new(config.tls_session_cache);
+        tls.ticketer = rustls::Ticketer::new().map_err(io::Error::other)?;
+    } else {
+        tls.session_storage = Arc::new(NoServerSessionStorage {});
+    }
+    Ok(tls)
+}
+
+/// Summarize the TLS settings the server runs with.
+pub fn summary(min_version: &str) -> io::Result<serde_json::Value> {
+    let versions = protocol_versions(min_version)?;
+
+    let suites: Vec<String> = DEFAULT_CIPHER_SUITES.iter()
+        .filter(|suite| versions.iter().any(|v| v.version == suite.version().version))
+        .map(|suite| format!("{:?}", suite.suite()))
+        .collect();
+
+    Ok(serde_json::json!({
+        "min_version": min_version,
+        "versions": versions.iter().map(|v| format!("{:?}", v.version)).collect::<Vec<_>>(),
+        "cipher_suites": suites,
+        "alpn_protocols": Vec::<String>::new(),
+        "client_auth": "none",
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::testing::TempDir;
+    use base64::{Engine, engine::general_purpose::STANDARD};
+
+    /// Write a PEM fixture for a test, made of the given entries, in a directory removed when it's dropped.
+    fn fixture(name: &str, entries: &[String]) -> (TempDir, std::path::PathBuf) {
+        let dir = TempDir::new(name);
+        let path = dir.join("fixture.pem");
+        std::fs::write(&path, entries.concat()).unwrap();
+        (dir, path)
+    }
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        format!("-----BEGIN {label}-----\n{}\n-----END {label}-----\n", STANDARD.encode(der))
+    }
+
+    #[test]
+    fn load_keys_reads_generated_keys() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let (_dir, path) = fixture("generated", &[cert.serialize_private_key_pem()]);
+
+        assert_eq!(load_keys(&path).unwrap().0, cert.serialize_private_key_der());
+    }
+
+    #[test]
+    fn load_keys_reads_rsa_and_ec_keys() {
+        let (_rsa_dir, rsa) = fixture("rsa", &[pem("RSA PRIVATE KEY", b"rsa key")]);
+        let (_ec_dir, ec) = fixture("ec", &[pem("EC PRIVATE KEY", b"ec key")]);
+
+        assert_eq!(load_keys(&rsa).unwrap().0, b"rsa key");
+        assert_eq!(load_keys(&ec).unwrap().0, b"ec key");
+    }
+
+    #[test]
+    fn load_keys_skips_other_entries() {
+        let (_dir, path) = fixture("bundle", &[pem("CERTIFICATE", b"cert"), pem("PRIVATE KEY", b"first"), pem("PRIVATE KEY", b"second")]);
+        assert_eq!(load_keys(&path).unwrap().0, b"first");
+
+        let certs = load_certs(&path).unwrap();
+        assert_eq!(certs.len(), 1);
+        assert_eq!(certs[0].0, b"cert");
+    }
+
+    #[test]
+    fn load_keys_fails_without_keys() {
+        let (_dir, path) = fixture("no-key", &[pem("CERTIFICATE", b"cert")]);
+        assert!(load_keys(&path).is_err());
+        assert!(load_keys(Path::new("/nonexistent/server.key")).is_err());
+    }
+
+    /// Handshake with a server over an in-memory stream, returning the resumption data of the session if it was resumed.
+    async fn handshake(acceptor: &tokio_rustls::TlsAcceptor, connector: &tokio_rustls::TlsConnector) -> Option<Vec<u8>> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let acceptor = acceptor.clone();
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept_with(server, |conn| conn.set_resumption_data(b"session")).await.unwrap();
+            let resumed = stream.get_ref().1.received_resumption_data().map(<[u8]>::to_vec);
+            stream.write_all(b"done").await.unwrap();
+            stream.shutdown().await.unwrap();
+            resumed
+        });
+
+        // Reading makes the client take in the session tickets sent after the handshake.
+        let mut stream = connector.connect(rustls::ServerName::try_from("localhost").unwrap(), client).await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"done");
+        server.await.unwrap()
+    }
+
+    async fn resumes(config: &ServerConfig) -> bool {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let key = PrivateKey(cert.serialize_private_key_der());
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config(vec![Certificate(der.clone())], key, config).unwrap()));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&Certificate(der)).unwrap();
+        let client = rustls::ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client));
+
+        assert_eq!(handshake(&acceptor, &connector).await, None);
+        handshake(&acceptor, &connector).await.is_some_and(|data| data == b"session")
+    }
+
+    #[tokio::test]
+    async fn reconnecting_clients_resume_their_session() {
+        assert!(resumes(&ServerConfig::default()).await);
+        assert!(resumes(&ServerConfig { tls_min_version: "1.3".to_owned(), ..ServerConfig::default() }).await);
+        assert!(!resumes(&ServerConfig { tls_session_cache: 0, ..ServerConfig::default() }).await);
+    }
+
+    #[test]
+    fn summary_follows_the_min_version() {
+        let all = summary("1.2").unwrap();
+        assert_eq!(all["min_version"], "1.2");
+        assert_eq!(all["versions"], serde_json::json!(["TLSv1_3", "TLSv1_2"]));
+
+        let tls13 = summary("1.3").unwrap();
+        assert_eq!(tls13["versions"], serde_json::json!(["TLSv1_3"]));
+        let suites = tls13["cipher_suites"].as_array().unwrap();
+        assert!(!suites.is_empty() && suites.iter().all(|suite| suite.as_str().unwrap().starts_with("TLS13_")));
+
+        assert!(summary("1.1").is_err());
+    }
+}
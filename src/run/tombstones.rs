@@ -0,0 +1,38 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::Mutex, time::{Duration, Instant}};
+
+/// Records of recently deleted notes, so they can be reported as gone rather than missing.
+#[derive(Debug, Default)]
+pub(crate) struct Tombstones {
+    window: Duration,
+    deleted: Mutex<HashMap<PathBuf, Instant>>,
+}
+
+impl Tombstones {
+    /// Create a new set of tombstones, which are kept for the given window.
+    pub fn new(window: Duration) -> Self {
+        Self { window, deleted: Mutex::new(HashMap::new()) }
+    }
+
+    /// Record that a note was deleted.
+    pub fn bury(&self, path: &Path) {
+        if self.window.is_zero() {
+            return;
+        }
+
+        let mut deleted = self.deleted.lock().unwrap();
+
+        // Drop the tombstones which have expired.
+        deleted.retain(|_, when| when.elapsed() < self.window);
+        deleted.insert(path.to_owned(), Instant::now());
+    }
+
+    /// Forget about a deleted note, e.g. because it was written again.
+    pub fn remove(&self, path: &Path) {
+        self.deleted.lock().unwrap().remove(path);
+    }
+
+    /// Check if a note was deleted within the window.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.deleted.lock().unwrap().get(path).is_some_and(|when| when.elapsed() < self.window)
+    }
+}